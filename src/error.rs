@@ -0,0 +1,144 @@
+use std::io;
+use thiserror::Error;
+
+/// Everything that can go wrong while running a command line.
+///
+/// `run_line` returns these instead of printing ad hoc, and the REPL reports
+/// them through `Display` and records `exit_code` as the command's status.
+#[derive(Debug, Error)]
+pub enum ShellError {
+    #[error("{0}: command not found")]
+    CommandNotFound(String),
+
+    #[error("{0}: Permission denied")]
+    PermissionDenied(String),
+
+    #[error("{command}: {}", describe(.source))]
+    ExecFailed { command: String, source: io::Error },
+
+    #[error("{path}: {}", describe(.source))]
+    RedirectFailed { path: String, source: io::Error },
+
+    #[error("syntax error near unexpected token `{0}'")]
+    SyntaxError(String),
+
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    UnexpectedEof(char),
+
+    #[error("{0}: event not found")]
+    EventNotFound(String),
+
+    #[error("{0}: unbound variable")]
+    UnboundVariable(String),
+
+    /// Something a restricted shell (`popper -r`) doesn't allow
+    #[error("restricted: cannot {0}")]
+    Restricted(String),
+
+    /// `exit` or `return` given a status that isn't a number
+    #[error("{builtin}: {arg}: numeric argument required")]
+    NumericArgumentRequired { builtin: String, arg: String },
+
+    #[error("{builtin}: `{name}': not a valid identifier")]
+    InvalidIdentifier { builtin: String, name: String },
+
+    #[error("{builtin}: {message}")]
+    Builtin { builtin: String, message: String },
+}
+
+impl ShellError {
+    /// Shorthand for a builtin reporting a failure in its own words.
+    pub fn builtin(builtin: &str, message: impl Into<String>) -> ShellError {
+        ShellError::Builtin {
+            builtin: builtin.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The exit status a failed command reports, following POSIX shells.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShellError::CommandNotFound(_) => 127,
+            ShellError::PermissionDenied(_) | ShellError::ExecFailed { .. } => 126,
+            ShellError::SyntaxError(_)
+            | ShellError::UnexpectedEof(_)
+            | ShellError::NumericArgumentRequired { .. } => 2,
+            ShellError::RedirectFailed { .. }
+            | ShellError::EventNotFound(_)
+            | ShellError::UnboundVariable(_)
+            | ShellError::Restricted(_)
+            | ShellError::InvalidIdentifier { .. }
+            | ShellError::Builtin { .. } => 1,
+        }
+    }
+}
+
+/// Describe an I/O error the way shells do: `No such file or directory`
+/// rather than `No such file or directory (os error 2)`.
+pub fn describe(err: &io::Error) -> String {
+    let text = err.to_string();
+    match text.find(" (os error") {
+        Some(end) => text[..end].to_string(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_follow_posix_conventions() {
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        let cases = [
+            (ShellError::CommandNotFound("nope".into()), 127),
+            (ShellError::PermissionDenied("./script".into()), 126),
+            (
+                ShellError::ExecFailed {
+                    command: "x".into(),
+                    source: io::Error::from(io::ErrorKind::Other),
+                },
+                126,
+            ),
+            (
+                ShellError::RedirectFailed {
+                    path: "out".into(),
+                    source: not_found,
+                },
+                1,
+            ),
+            (ShellError::SyntaxError("|".into()), 2),
+            (ShellError::UnexpectedEof('"'), 2),
+            (ShellError::EventNotFound("!$".into()), 1),
+            (ShellError::Restricted("change directory".into()), 1),
+            (
+                ShellError::NumericArgumentRequired {
+                    builtin: "exit".into(),
+                    arg: "x".into(),
+                },
+                2,
+            ),
+            (
+                ShellError::InvalidIdentifier {
+                    builtin: "export".into(),
+                    name: "1x".into(),
+                },
+                1,
+            ),
+            (ShellError::builtin("cd", "HOME not set"), 1),
+        ];
+
+        for (err, code) in cases {
+            assert_eq!(err.exit_code(), code, "{}", err);
+        }
+    }
+
+    #[test]
+    fn messages_drop_the_os_error_suffix() {
+        let err = ShellError::RedirectFailed {
+            path: "/missing/out".into(),
+            source: io::Error::from_raw_os_error(2),
+        };
+        assert_eq!(err.to_string(), "/missing/out: No such file or directory");
+    }
+}
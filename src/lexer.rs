@@ -0,0 +1,284 @@
+//! Splitting a command line into words, quoted strings and operators the
+//! way popper reads it. The shell uses it for highlighting, history's word
+//! designators and telling operators from quoted text; other tools can use
+//! it to split a line exactly as popper would.
+
+use std::ops::Range;
+use thiserror::Error;
+
+/// What a token is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Unquoted word text, including backslash escapes and `$(...)`
+    Word,
+    /// A control or redirection operator such as `|`, `&&` or `2>&1`
+    Operator,
+    /// A single- or double-quoted string, quotes included
+    Quoted,
+}
+
+/// A piece of a command line and the bytes it covers. Tokens with no blank
+/// between them belong to the same shell word, as in `a"b c"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+/// A quote that is never closed. The span runs from it to the end of the
+/// line.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("unexpected EOF while looking for matching `{quote}'")]
+pub struct UnterminatedQuote {
+    pub quote: char,
+    pub span: Range<usize>,
+}
+
+/// Operators, longest first so `>>` wins over `>`. The ones starting with
+/// a digit only count at the start of a word.
+const OPERATORS: &[&str] = &[
+    "2>&1", "1>&2", "2>&-", "1>&-", "<<<", "<<-", "1>>", "2>>", ">&2", ">&-", "&&", "||", "<<",
+    ">>", "1>", "2>", "|", "&", ";", "<", ">",
+];
+
+/// Split `line` into words, quoted strings and operators with their byte
+/// spans, following the quoting rules of `parse_arguments`. Blanks aren't
+/// tokens, and a `#` that begins a word ends the line.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, UnterminatedQuote> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut word_start = None;
+    let mut i = 0;
+
+    let end_word = |tokens: &mut Vec<Token>, word_start: &mut Option<usize>, end: usize| {
+        if let Some(start) = word_start.take() {
+            tokens.push(Token {
+                kind: TokenKind::Word,
+                span: start..end,
+            });
+        }
+    };
+
+    while let Some(ch) = line[i..].chars().next() {
+        let rest = &line[i..];
+        // Whether this character continues a word already under way
+        let in_word = word_start.is_some()
+            || tokens
+                .last()
+                .is_some_and(|token| token.span.end == i && token.kind != TokenKind::Operator);
+
+        match ch {
+            ' ' | '\t' => {
+                end_word(&mut tokens, &mut word_start, i);
+                i += 1;
+            }
+            '#' if !in_word => break,
+            '\'' | '"' => {
+                end_word(&mut tokens, &mut word_start, i);
+                let Some(len) = quoted_len(rest, ch) else {
+                    return Err(UnterminatedQuote {
+                        quote: ch,
+                        span: i..line.len(),
+                    });
+                };
+                tokens.push(Token {
+                    kind: TokenKind::Quoted,
+                    span: i..i + len,
+                });
+                i += len;
+            }
+            '\\' => {
+                word_start.get_or_insert(i);
+                i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+            }
+            '$' if rest.starts_with("$(") => {
+                word_start.get_or_insert(i);
+                i = match substitution_end(&rest[2..]) {
+                    Some(end) => i + 2 + end + 1,
+                    None => line.len(),
+                };
+            }
+            _ => {
+                let operator = OPERATORS
+                    .iter()
+                    .find(|op| rest.starts_with(**op) && !(in_word && op.starts_with(['1', '2'])));
+                match operator {
+                    Some(op) => {
+                        end_word(&mut tokens, &mut word_start, i);
+                        tokens.push(Token {
+                            kind: TokenKind::Operator,
+                            span: i..i + op.len(),
+                        });
+                        i += op.len();
+                    }
+                    None => {
+                        word_start.get_or_insert(i);
+                        i += ch.len_utf8();
+                    }
+                }
+            }
+        }
+    }
+    end_word(&mut tokens, &mut word_start, i);
+
+    Ok(tokens)
+}
+
+/// The words of `line` as typed, quotes and escapes included, with each
+/// operator as a word of its own. Tokens with no blank between them are
+/// joined, so `"my dir"/x` is one word.
+pub fn words(line: &str) -> Result<Vec<&str>, UnterminatedQuote> {
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    let mut previous = None;
+    for token in tokenize(line)? {
+        let joined = previous.is_some_and(|(kind, end)| {
+            end == token.span.start
+                && kind != TokenKind::Operator
+                && token.kind != TokenKind::Operator
+        });
+        previous = Some((token.kind, token.span.end));
+        match spans.last_mut() {
+            Some(span) if joined => span.end = token.span.end,
+            _ => spans.push(token.span),
+        }
+    }
+    Ok(spans.into_iter().map(|span| &line[span]).collect())
+}
+
+/// The length of the quoted string at the start of `text`, closing quote
+/// included, or `None` if it never closes. Inside double quotes a
+/// backslash escapes the next character.
+fn quoted_len(text: &str, quote: char) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, ch)) = chars.next() {
+        if ch == quote {
+            return Some(i + 1);
+        }
+        if ch == '\\' && quote == '"' {
+            chars.next();
+        }
+    }
+    None
+}
+
+/// Find the `)` that closes a command substitution, given the text just
+/// after its `$(`. Quotes, escapes, nested `$(...)` and parenthesised
+/// groups are skipped over, so `$(echo ")" $(date))` ends at the last `)`.
+/// Returns its byte offset, or `None` if it is never closed.
+pub fn substitution_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                chars.next();
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '$' if !in_single_quote && matches!(chars.peek(), Some((_, '('))) => {
+                let nested_end = i + 2 + substitution_end(&text[i + 2..])?;
+                while chars.next_if(|&(j, _)| j <= nested_end).is_some() {}
+            }
+            '(' if !in_single_quote && !in_double_quote => depth += 1,
+            ')' if !in_single_quote && !in_double_quote => {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_carry_their_kind_and_span() {
+        let line = "grep -v \"a b\"x|wc -l 2>&1 >>out.txt # note";
+        let tokens = tokenize(line).unwrap();
+        let pieces: Vec<(TokenKind, &str)> = tokens
+            .iter()
+            .map(|token| (token.kind, &line[token.span.clone()]))
+            .collect();
+
+        use TokenKind::*;
+        assert_eq!(
+            pieces,
+            [
+                (Word, "grep"),
+                (Word, "-v"),
+                (Quoted, "\"a b\""),
+                (Word, "x"),
+                (Operator, "|"),
+                (Word, "wc"),
+                (Word, "-l"),
+                (Operator, "2>&1"),
+                (Operator, ">>"),
+                (Word, "out.txt"),
+            ]
+        );
+        assert_eq!(tokens[2].span, 8..13);
+    }
+
+    #[test]
+    fn escapes_and_substitutions_stay_inside_words() {
+        let line = "echo a\\ b $(echo 'x y') \"q\\\"\"; ls&&x2>y";
+        let pieces: Vec<&str> = tokenize(line)
+            .unwrap()
+            .into_iter()
+            .map(|token| &line[token.span])
+            .collect();
+
+        assert_eq!(
+            pieces,
+            [
+                "echo",
+                "a\\ b",
+                "$(echo 'x y')",
+                "\"q\\\"\"",
+                ";",
+                "ls",
+                "&&",
+                "x2",
+                ">",
+                "y"
+            ]
+        );
+    }
+
+    #[test]
+    fn words_keep_their_quotes_and_join_adjacent_tokens() {
+        assert_eq!(
+            words("ls \"my dir\"/x 'a b'c|wc 2>&1").unwrap(),
+            ["ls", "\"my dir\"/x", "'a b'c", "|", "wc", "2>&1"]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_report_their_span() {
+        assert_eq!(
+            tokenize("echo ok 'never closed"),
+            Err(UnterminatedQuote {
+                quote: '\'',
+                span: 8..21,
+            })
+        );
+        assert_eq!(tokenize("echo \"it's\" '#'").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn substitution_end_skips_quotes_and_nesting() {
+        assert_eq!(substitution_end("date) rest"), Some(4));
+        assert_eq!(substitution_end("echo \")\" ')' \\) x) y"), Some(17));
+        assert_eq!(substitution_end("echo $(date) (a)) z"), Some(16));
+        assert_eq!(substitution_end("echo \"$(echo \")\")\")"), Some(18));
+        assert_eq!(substitution_end("echo (unclosed)"), None);
+    }
+}
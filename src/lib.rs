@@ -0,0 +1,3 @@
+//! The parts of popper that are useful outside the shell itself.
+
+pub mod lexer;
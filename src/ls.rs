@@ -0,0 +1,426 @@
+//! A native `ls` for systems without coreutils, built with the `ls` feature.
+
+use crate::error::{self, ShellError};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::cmp::Ordering;
+use std::fs::{self, Metadata};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// How to order the entries of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    Name,
+    Time,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    all: bool,
+    long: bool,
+    one_per_line: bool,
+    reverse: bool,
+    sort: SortBy,
+}
+
+/// A file to print, under the name it's printed with.
+struct Entry {
+    name: String,
+    metadata: Metadata,
+}
+
+/// `ls [-alr1tS] [FILE...]`: the files named, then the contents of the
+/// directories named (the current directory by default). `-a` includes
+/// names starting with `.`, `-l` prints one detailed line per file, and
+/// `-1` one name per line. Names sort by byte value, as in the C locale;
+/// `-t` sorts newest first, `-S` largest first and `-r` reverses the order.
+///
+/// `width` is the terminal width when the output goes to one: names are
+/// then laid out in columns. Otherwise they go one per line, like `ls`
+/// into a pipe. Times in `-l` are shown in UTC.
+pub fn ls_output(args: &[String], width: Option<usize>) -> (Vec<u8>, i32) {
+    let mut options = Options {
+        all: false,
+        long: false,
+        one_per_line: false,
+        reverse: false,
+        sort: SortBy::Name,
+    };
+    let mut args = args;
+    while let Some((flag, rest)) = args.split_first() {
+        if flag == "--" {
+            args = rest;
+            break;
+        }
+        let Some(letters) = flag.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+            break;
+        };
+        for letter in letters.chars() {
+            match letter {
+                'a' => options.all = true,
+                'l' => options.long = true,
+                '1' => options.one_per_line = true,
+                'r' => options.reverse = true,
+                't' => options.sort = SortBy::Time,
+                'S' => options.sort = SortBy::Size,
+                _ => {
+                    eprintln!("ls: -{}: invalid option", letter);
+                    return (Vec::new(), 2);
+                }
+            }
+        }
+        args = rest;
+    }
+    let operands = if args.is_empty() {
+        vec![".".to_string()]
+    } else {
+        args.to_vec()
+    };
+    let width = width.filter(|_| !options.one_per_line);
+
+    let mut status = 0;
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for operand in &operands {
+        match fs::metadata(operand).or_else(|_| fs::symlink_metadata(operand)) {
+            Ok(metadata) if metadata.is_dir() => dirs.push(operand),
+            Ok(_) => match fs::symlink_metadata(operand) {
+                Ok(metadata) => files.push(Entry {
+                    name: operand.clone(),
+                    metadata,
+                }),
+                Err(err) => status = report(operand, &err),
+            },
+            Err(err) => status = report(operand, &err),
+        }
+    }
+    // Directories come out in the requested order, after the files
+    let mut dirs: Vec<Entry> = dirs
+        .into_iter()
+        .filter_map(|dir| {
+            fs::metadata(dir).ok().map(|metadata| Entry {
+                name: dir.clone(),
+                metadata,
+            })
+        })
+        .collect();
+    sort_entries(&mut files, options);
+    sort_entries(&mut dirs, options);
+
+    let mut output = Vec::new();
+    if !files.is_empty() {
+        write_entries(&mut output, &files, Path::new(""), options, width, false);
+    }
+    for (i, dir) in dirs.iter().enumerate() {
+        if i > 0 || !files.is_empty() {
+            output.push(b'\n');
+        }
+        if operands.len() > 1 {
+            writeln!(output, "{}:", dir.name).ok();
+        }
+        match read_dir(Path::new(&dir.name), options) {
+            Ok(mut entries) => {
+                sort_entries(&mut entries, options);
+                write_entries(
+                    &mut output,
+                    &entries,
+                    Path::new(&dir.name),
+                    options,
+                    width,
+                    true,
+                );
+            }
+            Err(err) => {
+                eprintln!(
+                    "{}",
+                    ShellError::builtin(
+                        "ls",
+                        format!(
+                            "cannot open directory '{}': {}",
+                            dir.name,
+                            error::describe(&err)
+                        )
+                    )
+                );
+                status = 2;
+            }
+        }
+    }
+    (output, status)
+}
+
+/// Print the error for an operand that can't be listed, returning the
+/// status it makes `ls` exit with.
+fn report(operand: &str, err: &std::io::Error) -> i32 {
+    eprintln!(
+        "{}",
+        ShellError::builtin(
+            "ls",
+            format!("cannot access '{}': {}", operand, error::describe(err))
+        )
+    );
+    2
+}
+
+/// The entries of a directory, with `.` and `..` when `-a` asks for hidden
+/// names.
+fn read_dir(dir: &Path, options: Options) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if options.all {
+        for name in [".", ".."] {
+            entries.push(Entry {
+                name: name.to_string(),
+                metadata: fs::symlink_metadata(dir.join(name))?,
+            });
+        }
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !options.all {
+            continue;
+        }
+        // An entry removed since the directory was read is just skipped
+        if let Ok(metadata) = entry.metadata() {
+            entries.push(Entry { name, metadata });
+        }
+    }
+    Ok(entries)
+}
+
+fn sort_entries(entries: &mut [Entry], options: Options) {
+    entries.sort_by(|a, b| {
+        let order = match options.sort {
+            SortBy::Name => Ordering::Equal,
+            SortBy::Time => b.metadata.mtime().cmp(&a.metadata.mtime()),
+            SortBy::Size => b.metadata.len().cmp(&a.metadata.len()),
+        };
+        // Ties fall back to the name
+        let order = order.then_with(|| a.name.as_bytes().cmp(b.name.as_bytes()));
+        if options.reverse {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+}
+
+/// Write `entries` in the chosen format. `dir` is where they live, for
+/// reading symlink targets, and `in_dir` adds the `total` line of `-l`.
+fn write_entries(
+    output: &mut Vec<u8>,
+    entries: &[Entry],
+    dir: &Path,
+    options: Options,
+    width: Option<usize>,
+    in_dir: bool,
+) {
+    if options.long {
+        write_long(output, entries, dir, in_dir);
+        return;
+    }
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    match width {
+        Some(width) => output.extend(columns(&names, width).into_bytes()),
+        None => {
+            for name in names {
+                writeln!(output, "{}", name).ok();
+            }
+        }
+    }
+}
+
+/// Lay `names` out in columns that fit in `width`, filled top to bottom
+/// like `ls`, with as few rows as possible and two spaces between columns.
+fn columns(names: &[&str], width: usize) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    let lengths: Vec<usize> = names.iter().map(|name| name.chars().count()).collect();
+    let (rows, widths) = (1..=names.len())
+        .map(|rows| {
+            let widths: Vec<usize> = lengths
+                .chunks(rows)
+                .map(|column| column.iter().copied().max().unwrap_or(0))
+                .collect();
+            (rows, widths)
+        })
+        .find(|(_, widths)| widths.iter().sum::<usize>() + 2 * (widths.len() - 1) <= width)
+        .unwrap_or_else(|| (names.len(), vec![0]));
+
+    let mut text = String::new();
+    for row in 0..rows {
+        let cells: Vec<usize> = (row..names.len()).step_by(rows).collect();
+        for (column, &index) in cells.iter().enumerate() {
+            if column + 1 < cells.len() {
+                text.push_str(&format!(
+                    "{:<width$}",
+                    names[index],
+                    width = widths[column] + 2
+                ));
+            } else {
+                text.push_str(names[index]);
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// The `-l` format: type and permissions, link count, owner, group, size,
+/// modification time and name, with the columns aligned.
+fn write_long(output: &mut Vec<u8>, entries: &[Entry], dir: &Path, in_dir: bool) {
+    if in_dir {
+        // st_blocks counts 512-byte blocks; ls reports 1K ones
+        let blocks: u64 = entries.iter().map(|entry| entry.metadata.blocks()).sum();
+        writeln!(output, "total {}", blocks.div_ceil(2)).ok();
+    }
+    let now = SystemTime::now();
+    let rows: Vec<[String; 6]> = entries
+        .iter()
+        .map(|entry| {
+            let metadata = &entry.metadata;
+            let mut name = entry.name.clone();
+            if metadata.file_type().is_symlink() {
+                if let Ok(target) = fs::read_link(dir.join(&entry.name)) {
+                    name = format!("{} -> {}", name, target.display());
+                }
+            }
+            [
+                mode_string(metadata),
+                metadata.nlink().to_string(),
+                User::from_uid(Uid::from_raw(metadata.uid()))
+                    .ok()
+                    .flatten()
+                    .map_or_else(|| metadata.uid().to_string(), |user| user.name),
+                Group::from_gid(Gid::from_raw(metadata.gid()))
+                    .ok()
+                    .flatten()
+                    .map_or_else(|| metadata.gid().to_string(), |group| group.name),
+                metadata.len().to_string(),
+                format_time(metadata.modified().unwrap_or(now), now) + " " + &name,
+            ]
+        })
+        .collect();
+
+    let width = |column: usize| rows.iter().map(|row| row[column].len()).max().unwrap_or(0);
+    let (links, owner, group, size) = (width(1), width(2), width(3), width(4));
+    for [mode, nlink, user, grp, len, rest] in &rows {
+        writeln!(
+            output,
+            "{} {:>links$} {:<owner$} {:<group$} {:>size$} {}",
+            mode, nlink, user, grp, len, rest
+        )
+        .ok();
+    }
+}
+
+/// The `drwxr-xr-x` column of `ls -l`, including setuid, setgid and
+/// sticky bits.
+fn mode_string(metadata: &Metadata) -> String {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    };
+
+    let mode = metadata.permissions().mode();
+    let mut text = String::from(kind);
+    for (shift, special, set_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        text.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        text.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        text.push(match (mode & special != 0, bits & 0o1 != 0) {
+            (true, true) => set_char,
+            (true, false) => set_char.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    text
+}
+
+/// `Oct 17 09:41` for recent times, or `Oct 17  2025` for ones more than
+/// six months old or in the future, like `ls -l`.
+fn format_time(time: SystemTime, now: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    const SIX_MONTHS: Duration = Duration::from_secs(365 * 24 * 60 * 60 / 2);
+
+    let seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    };
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let recent = now.duration_since(time).is_ok_and(|age| age < SIX_MONTHS);
+    if recent {
+        let minutes = seconds.rem_euclid(86_400) / 60;
+        format!(
+            "{} {:>2} {:02}:{:02}",
+            MONTHS[month - 1],
+            day,
+            minutes / 60,
+            minutes % 60
+        )
+    } else {
+        format!("{} {:>2}  {}", MONTHS[month - 1], day, year)
+    }
+}
+
+/// The proleptic Gregorian `(year, month, day)` of a count of days since
+/// 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as usize, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_fill_columns_top_to_bottom() {
+        let names = ["a", "bb", "ccc", "d", "eeeee"];
+
+        assert_eq!(columns(&names, 80), "a  bb  ccc  d  eeeee\n");
+        assert_eq!(columns(&names, 12), "a    d\nbb   eeeee\nccc\n");
+        assert_eq!(columns(&names, 1), "a\nbb\nccc\nd\neeeee\n");
+    }
+
+    #[test]
+    fn days_convert_to_calendar_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+}
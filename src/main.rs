@@ -1,21 +1,72 @@
+mod error;
+#[cfg(feature = "ls")]
+mod ls;
+
+use error::ShellError;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use popper::lexer::{self, substitution_end};
 use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
 use rustyline::validate::Validator;
 use rustyline::{CompletionType, Config, Context, Editor, Helper};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::os::fd::{AsFd, FromRawFd, OwnedFd};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use terminal_size::{Height, Width};
 
-struct ShellHelper;
+/// Tab completion for the editor. It remembers the executables on `PATH`
+/// so a Tab press doesn't re-read every directory.
+#[derive(Default)]
+struct ShellHelper {
+    path_cache: RefCell<PathCache>,
+    // The shell's background jobs, by number with their command lines,
+    // copied before each prompt
+    jobs: Vec<(usize, String)>,
+}
 
 impl Helper for ShellHelper {}
 
+/// The commands on a `PATH` and where each one runs from, kept while `PATH`
+/// and the modification times of its directories stay the same.
+#[derive(Default)]
+struct PathCache {
+    path: Option<String>,
+    stamps: Vec<Option<SystemTime>>,
+    commands: BTreeMap<String, String>,
+}
+
+impl PathCache {
+    /// Rescan `path` unless it's the one cached and none of its directories
+    /// changed since. Returns whether it rescanned.
+    fn refresh(&mut self, path: &str) -> bool {
+        let stamps: Vec<Option<SystemTime>> = path_dirs(path)
+            .map(|dir| std::fs::metadata(dir).and_then(|m| m.modified()).ok())
+            .collect();
+        if self.path.as_deref() == Some(path) && self.stamps == stamps {
+            return false;
+        }
+        self.path = Some(path.to_string());
+        self.stamps = stamps;
+        self.commands = path_commands(path);
+        true
+    }
+}
+
 impl Completer for ShellHelper {
     type Candidate = Pair;
 
@@ -25,456 +76,3170 @@ impl Completer for ShellHelper {
         pos: usize,
         _ctx: &Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Pair>)> {
-        let builtins = ["echo ", "exit ", "type ", "pwd", "cd ", "history"];
-
         let input = &line[..pos];
-        let mut candidates = Vec::new();
 
-        // Check builtins first
-        for builtin in &builtins {
-            if builtin.starts_with(input) && !input.is_empty() {
-                candidates.push(Pair {
-                    display: builtin.to_string(),
-                    replacement: builtin.to_string(),
-                });
+        // Past the first word, complete file and directory names instead
+        let trimmed = input.trim_start();
+        if let Some(space) = trimmed.rfind([' ', '\t']) {
+            let word_start = pos - (trimmed.len() - space - 1);
+            let word = &line[word_start..pos];
+            // `user@host` arguments of ssh-like commands complete known hosts
+            let command = trimmed.split_whitespace().next().unwrap_or("");
+            if let Some(candidates) = complete_host(command, word) {
+                return Ok((word_start, candidates));
+            }
+            if let Some(candidates) = complete_job(command, word, &self.jobs) {
+                return Ok((word_start, candidates));
             }
-        }
 
-        // Search for executables in PATH
-        if !input.is_empty() {
-            if let Ok(path_env) = env::var("PATH") {
-                for dir in path_env.split(':') {
-                    let path = Path::new(dir);
-                    if let Ok(entries) = std::fs::read_dir(path) {
-                        for entry in entries.flatten() {
-                            if let Ok(file_name) = entry.file_name().into_string() {
-                                if file_name.starts_with(input) {
-                                    // Check if executable
-                                    if let Ok(metadata) = entry.metadata() {
-                                        let permissions = metadata.permissions();
-                                        if permissions.mode() & 0o111 != 0 {
-                                            // Avoid duplicates
-                                            if !candidates
-                                                .iter()
-                                                .any(|c| c.replacement.trim() == file_name)
-                                            {
-                                                candidates.push(Pair {
-                                                    display: file_name.clone(),
-                                                    replacement: format!("{} ", file_name),
-                                                });
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+            if command == "cd" {
+                return Ok((word_start, complete_directory(word)));
             }
+            return Ok((word_start, complete_path(word)));
         }
 
-        // Sort candidates alphabetically
-        candidates.sort_by(|a, b| a.display.cmp(&b.display));
-
-        Ok((0, candidates))
+        if input.is_empty() {
+            return Ok((0, Vec::new()));
+        }
+        let mut cache = self.path_cache.borrow_mut();
+        cache.refresh(&search_path());
+        Ok((0, complete_command_word(input, cache.commands.keys())))
     }
 }
 
-impl Hinter for ShellHelper {
-    type Hint = String;
+/// Complete the command word, which may open with a quote: `"ec` finds
+/// the commands starting with `ec` and completes to `"echo" `, quoted the
+/// same way and closed.
+fn complete_command_word<'a>(
+    word: &str,
+    executables: impl IntoIterator<Item = &'a String>,
+) -> Vec<Pair> {
+    let Some(quote) = word.chars().next().filter(|ch| matches!(ch, '"' | '\'')) else {
+        return complete_command(word, executables);
+    };
+    let prefix = &word[1..];
+    let prefix = prefix.strip_suffix(quote).unwrap_or(prefix);
+
+    complete_command(prefix, executables)
+        .into_iter()
+        .map(|candidate| {
+            let name = candidate.replacement.trim_end();
+            let space = &candidate.replacement[name.len()..];
+            Pair {
+                replacement: format!("{}{}{}{}", quote, name, quote, space),
+                display: candidate.display,
+            }
+        })
+        .collect()
 }
 
-impl Highlighter for ShellHelper {}
+/// Complete a command name from the builtins and `executables`, the names
+/// of the executables on `PATH`.
+fn complete_command<'a>(
+    prefix: &str,
+    executables: impl IntoIterator<Item = &'a String>,
+) -> Vec<Pair> {
+    let builtins = [
+        "echo ",
+        "exit ",
+        "type ",
+        "pwd",
+        "cd ",
+        "history",
+        "builtin ",
+        "kill ",
+        "repeat ",
+        "loop ",
+        "export ",
+        "unset ",
+        "compgen ",
+        "alias ",
+        "unalias ",
+        "source ",
+        "return ",
+        "set ",
+        "jobs",
+        "fg ",
+        "grep ",
+        "wc ",
+        "which ",
+        "true ",
+        "false ",
+        "test ",
+        "[ ",
+        "env ",
+        #[cfg(feature = "ls")]
+        "ls ",
+    ];
 
-impl Validator for ShellHelper {}
+    let mut candidates = Vec::new();
 
-fn save_history_to_file(command_history: &[String]) {
-    if let Ok(histfile) = env::var("HISTFILE") {
-        if let Ok(mut file) = File::create(&histfile) {
-            for cmd in command_history {
-                writeln!(file, "{}", cmd).ok();
-            }
+    // Check builtins first
+    for builtin in &builtins {
+        if builtin.starts_with(prefix) {
+            candidates.push(Pair {
+                display: builtin.to_string(),
+                replacement: builtin.to_string(),
+            });
         }
     }
-}
 
-fn main() {
-    let config = Config::builder()
-        .completion_type(CompletionType::List)
-        .build();
-    let mut rl = Editor::with_config(config).unwrap();
-    rl.set_helper(Some(ShellHelper));
+    // Then executables, skipping names a builtin already offered
+    for name in executables {
+        if name.starts_with(prefix) && !candidates.iter().any(|c| c.replacement.trim() == name) {
+            candidates.push(Pair {
+                display: name.clone(),
+                replacement: format!("{} ", name),
+            });
+        }
+    }
 
-    // Track command history
-    let mut command_history: Vec<String> = Vec::new();
-    // Track the last index that was appended to file (for history -a)
-    let mut last_appended_index: usize = 0;
-
-    // Load history from HISTFILE if set
-    if let Ok(histfile) = env::var("HISTFILE") {
-        if let Ok(file) = File::open(&histfile) {
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                if let Ok(cmd) = line {
-                    // Skip empty lines
-                    if !cmd.trim().is_empty() {
-                        command_history.push(cmd.clone());
-                        rl.add_history_entry(&cmd).ok();
-                    }
+    // Sort candidates alphabetically
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+
+    candidates
+}
+
+/// Every command that could run from the directories in `path`, by name,
+/// with the path it resolves to. Like `resolve_in_path`, the first
+/// directory holding a name wins.
+fn path_commands(path: &str) -> BTreeMap<String, String> {
+    let mut commands = BTreeMap::new();
+    for dir in path_dirs(path) {
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let full_path = entry.path();
+                if is_executable_file(&full_path) {
+                    // Names that aren't UTF-8 are still offered, lossily
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    commands
+                        .entry(name)
+                        .or_insert_with(|| full_path.to_string_lossy().into_owned());
                 }
             }
         }
     }
+    commands
+}
 
-    loop {
-        let readline = rl.readline("$ ");
+/// Commands whose `user@host` arguments complete hostnames.
+const SSH_COMMANDS: [&str; 3] = ["ssh", "scp", "rsync"];
 
-        let input = match readline {
-            Ok(line) => line,
-            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
-                // Save history before exiting
-                save_history_to_file(&command_history);
-                break;
-            }
-            Err(_) => {
-                continue;
-            }
+/// Complete the host part of a `user@host` word for an ssh-like command.
+/// Hosts come from `~/.ssh/config` and `~/.ssh/known_hosts`; for scp and
+/// rsync the host is followed by `:` so a remote path can come next.
+fn complete_host(command: &str, word: &str) -> Option<Vec<Pair>> {
+    if !SSH_COMMANDS.contains(&command) {
+        return None;
+    }
+    let (user, prefix) = word.split_once('@')?;
+    let suffix = if command == "ssh" { " " } else { ":" };
+
+    let candidates = ssh_hosts()
+        .iter()
+        .filter(|host| host.starts_with(prefix))
+        .map(|host| Pair {
+            display: host.clone(),
+            replacement: format!("{}@{}{}", user, host, suffix),
+        })
+        .collect();
+    Some(candidates)
+}
+
+/// Complete a `%N` job spec for `fg`, `bg` or `kill %`, showing each job
+/// with its command line.
+fn complete_job(command: &str, word: &str, jobs: &[(usize, String)]) -> Option<Vec<Pair>> {
+    let wanted = match command {
+        "fg" | "bg" => word.is_empty() || word.starts_with('%'),
+        "kill" => word.starts_with('%'),
+        _ => false,
+    };
+    if !wanted {
+        return None;
+    }
+
+    let candidates = jobs
+        .iter()
+        .map(|(id, command)| (format!("%{}", id), command))
+        .filter(|(spec, _)| spec.starts_with(word))
+        .map(|(spec, command)| Pair {
+            display: format!("{}  {}", spec, command),
+            replacement: format!("{} ", spec),
+        })
+        .collect();
+    Some(candidates)
+}
+
+/// Hosts named in the user's ssh files, sorted, read once per session.
+fn ssh_hosts() -> &'static [String] {
+    static HOSTS: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+    HOSTS.get_or_init(|| {
+        let Ok(home) = env::var("HOME") else {
+            return Vec::new();
         };
+        let ssh_dir = Path::new(&home).join(".ssh");
+        let read = |name: &str| std::fs::read_to_string(ssh_dir.join(name)).unwrap_or_default();
 
-        let input = input.trim();
+        let mut hosts = parse_ssh_config_hosts(&read("config"));
+        hosts.extend(parse_known_hosts(&read("known_hosts")));
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    })
+}
 
-        // Add non-empty commands to history
-        if !input.is_empty() {
-            command_history.push(input.to_string());
-            // Also add to rustyline's history for up/down arrow navigation
-            rl.add_history_entry(input).ok();
-        }
+/// Host aliases from `Host` lines of an ssh config, skipping patterns.
+fn parse_ssh_config_hosts(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let keyword = words.next()?;
+            keyword.eq_ignore_ascii_case("host").then_some(words)
+        })
+        .flatten()
+        .filter(|host| !host.contains(['*', '?', '!']))
+        .map(str::to_string)
+        .collect()
+}
 
-        // Parse input first to check for pipelines
-        let parts = parse_arguments(input);
-        if parts.is_empty() {
+/// Hostnames from a known_hosts file. Hashed entries can't be recovered and
+/// are skipped; `[host]:port` entries give just the host.
+fn parse_known_hosts(text: &str) -> Vec<String> {
+    let mut hosts = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(mut names) = fields.next() else {
             continue;
+        };
+        // `@cert-authority` and `@revoked` markers come before the names
+        if names.starts_with('@') {
+            match fields.next() {
+                Some(next) => names = next,
+                None => continue,
+            }
         }
-
-        // Check for pipeline first (before handling built-ins)
-        if parts.iter().any(|p| p == "|") {
-            execute_pipeline(&parts);
+        if names.starts_with('#') || names.starts_with('|') {
             continue;
         }
-
-        // Now handle built-in commands that don't involve pipelines
-        if input.starts_with("exit") {
-            let exit_parts: Vec<&str> = input.split_whitespace().collect();
-            let exit_code = if exit_parts.len() > 1 {
-                exit_parts[1].parse::<i32>().unwrap_or(0)
-            } else {
-                0
+        for name in names.split(',') {
+            let host = match name.strip_prefix('[') {
+                Some(bracketed) => bracketed.split(']').next().unwrap_or(""),
+                None => name,
             };
-            // Save history before exiting
-            save_history_to_file(&command_history);
-            std::process::exit(exit_code);
+            if !host.is_empty() && !host.contains(['*', '?', '!']) {
+                hosts.push(host.to_string());
+            }
         }
+    }
+    hosts
+}
 
-        if input.starts_with("echo ") {
-            let (cmd_args, stdout_file, stdout_append, stderr_file, _stderr_append) =
-                parse_redirection(&parts[1..]); // Skip "echo" itself
-
-            let output_text = cmd_args.join(" ");
+/// Complete a partial path. The word is split at its last `/`: the part
+/// before it names the directory to list (the current directory if absent)
+/// and the part after it is matched against that directory's entries.
+fn complete_path(word: &str) -> Vec<Pair> {
+    complete_path_in(Path::new("."), word)
+}
 
-            if let Some(file_path) = stdout_file {
-                // Redirect stdout to file
-                let file_result = if stdout_append {
-                    std::fs::OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(&file_path)
-                } else {
-                    File::create(&file_path)
-                };
+/// Complete a `cd` argument: only directories (including symlinks to them),
+/// from the current directory and from each `CDPATH` entry.
+fn complete_directory(word: &str) -> Vec<Pair> {
+    let is_dir = |candidate: &Pair| candidate.replacement.ends_with('/');
+    let mut candidates: Vec<Pair> = complete_path(word).into_iter().filter(is_dir).collect();
 
-                match file_result {
-                    Ok(mut file) => {
-                        writeln!(file, "{}", output_text).ok();
-                    }
-                    Err(_) => {
-                        eprintln!("Failed to create file: {}", file_path);
-                    }
+    if is_cdpath_candidate(word) {
+        for base in cdpath_entries() {
+            for candidate in complete_path_in(Path::new(&base), word) {
+                let is_new = !candidates
+                    .iter()
+                    .any(|c| c.replacement == candidate.replacement);
+                if is_dir(&candidate) && is_new {
+                    candidates.push(candidate);
                 }
-            } else {
-                // Print to stdout
-                println!("{}", output_text);
             }
+        }
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    }
+    candidates
+}
 
-            // Create stderr file even if empty (echo doesn't write to stderr)
-            if let Some(file_path) = stderr_file {
-                File::create(&file_path).ok();
+/// Like `complete_path`, but relative words are looked up under `base`.
+/// Replacements keep the word as typed, without `base` prepended.
+fn complete_path_in(base: &Path, word: &str) -> Vec<Pair> {
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let dir = completion_dir(base, dir_part, &|name| env::var(name).ok());
+
+    let mut candidates = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            // Names that aren't UTF-8 are still offered, lossily
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            // Hidden files are only offered when explicitly asked for
+            if !file_name.starts_with(prefix)
+                || (file_name.starts_with('.') && !prefix.starts_with('.'))
+            {
+                continue;
             }
 
-            continue;
+            // Follow symlinks so links to directories complete like directories
+            let is_dir = std::fs::metadata(entry.path())
+                .map(|metadata| metadata.is_dir())
+                .unwrap_or(false);
+            if is_dir {
+                candidates.push(Pair {
+                    display: format!("{}/", file_name),
+                    replacement: format!("{}{}/", dir_part, file_name),
+                });
+            } else {
+                candidates.push(Pair {
+                    display: file_name.clone(),
+                    replacement: format!("{}{} ", dir_part, file_name),
+                });
+            }
         }
+    }
+
+    let mut candidates = apply_fignore(candidates, &fignore_suffixes());
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
 
-        if input == "pwd" {
-            match env::current_dir() {
-                Ok(path) => println!("{}", path.display()),
-                Err(_) => eprintln!("pwd: error getting current directory"),
+/// The directory a completion lists: `dir_part` under `base`, or in a home
+/// directory when it starts with `~/` or `~user/`.
+fn completion_dir(base: &Path, dir_part: &str, lookup: &VariableLookup) -> PathBuf {
+    if dir_part.starts_with('~') {
+        if let Some(word) = split_words(dir_part, Some(lookup)).pop() {
+            if !word.text.starts_with('~') {
+                return PathBuf::from(word.text);
             }
-            continue;
         }
+    }
+    base.join(dir_part)
+}
 
-        if input.starts_with("cd ") {
-            let path = &input[3..]; // Skip "cd "
+/// Suffixes listed in the colon-separated `FIGNORE` variable.
+fn fignore_suffixes() -> Vec<String> {
+    env::var("FIGNORE")
+        .map(|fignore| {
+            fignore
+                .split(':')
+                .filter(|suffix| !suffix.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-            // Expand ~ to HOME directory
-            let expanded_path = if path == "~" || path.starts_with("~/") {
-                if let Ok(home) = env::var("HOME") {
-                    if path == "~" {
-                        home
-                    } else {
-                        path.replacen("~", &home, 1)
-                    }
-                } else {
-                    path.to_string()
-                }
-            } else {
-                path.to_string()
-            };
+/// Drop file candidates ending in an ignored suffix. Directories are always
+/// kept, and if every candidate would be dropped they are all offered anyway.
+fn apply_fignore(candidates: Vec<Pair>, suffixes: &[String]) -> Vec<Pair> {
+    if suffixes.is_empty() {
+        return candidates;
+    }
 
-            if let Err(_) = env::set_current_dir(&expanded_path) {
-                println!("cd: {}: No such file or directory", path);
-            }
-            continue;
-        }
+    let kept: Vec<Pair> = candidates
+        .iter()
+        .filter(|c| {
+            c.display.ends_with('/') || !suffixes.iter().any(|s| c.display.ends_with(s.as_str()))
+        })
+        .cloned()
+        .collect();
+    if kept.is_empty() {
+        candidates
+    } else {
+        kept
+    }
+}
 
-        if input.starts_with("type ") {
-            let cmd = &input[5..]; // Skip "type "
-            if cmd == "echo"
-                || cmd == "exit"
-                || cmd == "type"
-                || cmd == "pwd"
-                || cmd == "cd"
-                || cmd == "history"
-            {
-                println!("{} is a shell builtin", cmd);
-            } else {
-                // Search for executable in PATH
-                if let Some(path) = find_in_path(cmd) {
-                    println!("{} is {}", cmd, path);
-                } else {
-                    println!("{}: not found", cmd);
-                }
-            }
-            continue;
-        }
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
 
-        if input == "history" || input.starts_with("history ") {
-            // Check for history -r <path>
-            if input.starts_with("history -r ") {
-                let path = &input[11..]; // Skip "history -r "
-
-                // Read history from file
-                if let Ok(file) = File::open(path) {
-                    let reader = BufReader::new(file);
-                    for line in reader.lines() {
-                        if let Ok(cmd) = line {
-                            // Skip empty lines
-                            if !cmd.trim().is_empty() {
-                                command_history.push(cmd.clone());
-                                rl.add_history_entry(&cmd).ok();
-                            }
-                        }
-                    }
-                } else {
-                    eprintln!("history: {}: No such file or directory", path);
-                }
-                continue;
-            }
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Owned(highlight_line(line))
+    }
 
-            // Check for history -w <path>
-            if input.starts_with("history -w ") {
-                let path = &input[11..]; // Skip "history -w "
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
 
-                // Write history to file
-                match File::create(path) {
-                    Ok(mut file) => {
-                        for cmd in &command_history {
-                            writeln!(file, "{}", cmd).ok();
-                        }
-                    }
-                    Err(_) => {
-                        eprintln!("history: {}: Cannot create file", path);
-                    }
-                }
-                continue;
-            }
+/// Color a line being edited: operators bold, quoted strings green, and
+/// from an unclosed quote to the end red.
+fn highlight_line(line: &str) -> String {
+    const BOLD: &str = "\x1b[1m";
+    const GREEN: &str = "\x1b[32m";
+    const RED: &str = "\x1b[31m";
+    const RESET: &str = "\x1b[0m";
 
-            // Check for history -a <path>
-            if input.starts_with("history -a ") {
-                let path = &input[11..]; // Skip "history -a "
-
-                // Append new commands to file
-                match std::fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(path)
-                {
-                    Ok(mut file) => {
-                        // Append only commands that haven't been appended yet
-                        for cmd in &command_history[last_appended_index..] {
-                            writeln!(file, "{}", cmd).ok();
-                        }
-                        // Update the last appended index
-                        last_appended_index = command_history.len();
-                    }
-                    Err(_) => {
-                        eprintln!("history: {}: Cannot create file", path);
-                    }
-                }
-                continue;
-            }
+    let tokens = match lexer::tokenize(line) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            return format!(
+                "{}{}{}{}",
+                &line[..err.span.start],
+                RED,
+                &line[err.span],
+                RESET
+            )
+        }
+    };
 
-            let limit = if input == "history" {
-                None
-            } else {
-                let n_str = &input[8..]; // Skip "history "
-                n_str.parse::<usize>().ok()
-            };
+    let mut highlighted = String::new();
+    let mut copied = 0;
+    for token in tokens {
+        let color = match token.kind {
+            lexer::TokenKind::Word => continue,
+            lexer::TokenKind::Operator => BOLD,
+            lexer::TokenKind::Quoted => GREEN,
+        };
+        highlighted.push_str(&line[copied..token.span.start]);
+        highlighted.push_str(color);
+        highlighted.push_str(&line[token.span.clone()]);
+        highlighted.push_str(RESET);
+        copied = token.span.end;
+    }
+    highlighted.push_str(&line[copied..]);
+    highlighted
+}
 
-            let entries_to_show = if let Some(n) = limit {
-                // Show last n entries
-                let start_index = command_history.len().saturating_sub(n);
-                &command_history[start_index..]
-            } else {
-                // Show all entries
-                &command_history[..]
-            };
+impl Validator for ShellHelper {}
 
-            let start_number = command_history.len() - entries_to_show.len() + 1;
-            for (index, cmd) in entries_to_show.iter().enumerate() {
-                println!("{:5}  {}", start_number + index, cmd);
-            }
-            continue;
+/// Decide whether a line typed at the prompt may be stored in history.
+///
+/// `HISTCONTROL=ignorespace` drops lines starting with a space, and
+/// `HISTIGNORE` is a colon-separated list of glob patterns matched against the
+/// whole line (e.g. `*--password*`), so secrets pasted on the command line
+/// never reach the history file.
+fn should_record_history(raw_line: &str) -> bool {
+    if let Ok(histcontrol) = env::var("HISTCONTROL") {
+        let ignore_space = histcontrol
+            .split(':')
+            .any(|opt| opt == "ignorespace" || opt == "ignoreboth");
+        if ignore_space && raw_line.starts_with(' ') {
+            return false;
         }
+    }
 
-        // Try to execute as external program
+    if let Ok(histignore) = env::var("HISTIGNORE") {
+        let line = raw_line.trim();
+        if histignore
+            .split(':')
+            .filter(|pattern| !pattern.is_empty())
+            .any(|pattern| glob_match(pattern, line))
+        {
+            return false;
+        }
+    }
 
-        // Check for output redirection
-        let (cmd_parts, stdout_file, stdout_append, stderr_file, stderr_append) =
-            parse_redirection(&parts);
+    true
+}
 
-        if cmd_parts.is_empty() {
-            continue;
+/// The external command the shell is waiting on, or 0. SIGINT is passed on
+/// to it.
+static FOREGROUND_CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// State that lives for the whole interactive session.
+struct Shell {
+    editor: Editor<ShellHelper, DefaultHistory>,
+    // All interactive output goes through one buffered writer that is flushed
+    // after every command, so builtin and external output never interleave
+    // out of order.
+    out: BufWriter<io::Stdout>,
+    // Track command history
+    command_history: Vec<String>,
+    // Track the last index that was appended to file (for history -a)
+    last_appended_index: usize,
+    // Exit status of the most recent command
+    last_status: i32,
+    // Shell-local variables; exported ones live in the process environment
+    variables: HashMap<String, String>,
+    // Set by SIGWINCH (and initially) so COLUMNS/LINES get refreshed
+    resized: Arc<AtomicBool>,
+    // Command-word shortcuts defined with `alias`
+    aliases: HashMap<String, String>,
+    // Where `cd -` goes back to
+    previous_dir: Option<PathBuf>,
+    // How many `$(...)` substitutions are running, and whether one of them
+    // hit `exit`, which ends the substitution rather than the shell
+    substitution_depth: usize,
+    substitution_exit: bool,
+    // How many files `source` is running, and whether one of them hit
+    // `return`, which ends that file rather than the shell
+    source_depth: usize,
+    returning: bool,
+    // Here-document bodies read with the current line, waiting for the
+    // commands that announced them
+    here_docs: VecDeque<HereDoc>,
+    // `set -u`: expanding an unset variable is an error
+    nounset: bool,
+    // `popper -r`: no cd, PATH changes, commands by path or output redirection
+    restricted: bool,
+    // Commands started with a trailing `&` that haven't been reaped yet
+    jobs: Vec<Job>,
+    // The last command was an `exit` held up by running jobs, so another
+    // one goes through
+    exit_warned: bool,
+}
+
+/// A command running in the background.
+struct Job {
+    // Job number, as in `[1]`
+    id: usize,
+    // The command line as typed, without the `&`
+    command: String,
+    child: std::process::Child,
+}
+
+impl Shell {
+    fn new() -> Shell {
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = Editor::with_config(config).unwrap();
+        editor.set_helper(Some(ShellHelper::default()));
+
+        // Registered after the editor so its own SIGWINCH handler still runs
+        let resized = Arc::new(AtomicBool::new(true));
+        signal_hook::flag::register(signal_hook::consts::SIGWINCH, resized.clone()).ok();
+
+        // Ctrl-C interrupts the foreground command, never the shell. On a
+        // terminal the child gets the signal from the terminal itself; a
+        // SIGINT sent to a non-interactive shell is passed on to it.
+        let forward = !io::stdin().is_terminal();
+        // SAFETY: the handler only does an atomic load and kill(2), both of
+        // which are async-signal-safe
+        unsafe {
+            signal_hook::low_level::register(signal_hook::consts::SIGINT, move || {
+                let pid = FOREGROUND_CHILD.load(Ordering::Relaxed);
+                if forward && pid > 0 {
+                    nix::sys::signal::kill(Pid::from_raw(pid), Signal::SIGINT).ok();
+                }
+            })
+            .ok();
         }
 
-        let cmd = cmd_parts[0].as_str();
+        let mut shell = Shell {
+            editor,
+            out: BufWriter::new(io::stdout()),
+            command_history: Vec::new(),
+            last_appended_index: 0,
+            last_status: 0,
+            variables: HashMap::new(),
+            resized,
+            aliases: HashMap::new(),
+            previous_dir: None,
+            substitution_depth: 0,
+            substitution_exit: false,
+            source_depth: 0,
+            returning: false,
+            here_docs: VecDeque::new(),
+            nounset: false,
+            restricted: false,
+            jobs: Vec::new(),
+            exit_warned: false,
+        };
+        shell.refresh_terminal_size();
+        shell
+    }
 
-        // Check if it's a builtin that doesn't need arguments
-        if cmd == "exit" || cmd == "echo" || cmd == "type" || cmd == "pwd" || cmd == "cd" {
-            println!("{}: command not found", input);
-            continue;
+    /// Re-read the terminal size into `COLUMNS` and `LINES` if the window
+    /// changed since the last look. Without a terminal they are left alone.
+    fn refresh_terminal_size(&mut self) {
+        if !self.resized.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Some((Width(columns), Height(lines))) = terminal_size::terminal_size() {
+            self.set_terminal_size(columns, lines);
         }
+    }
 
-        // Search for executable in PATH
-        if let Some(path) = find_in_path(cmd) {
-            let args = &cmd_parts[1..];
+    fn set_terminal_size(&mut self, columns: u16, lines: u16) {
+        self.set_variable("COLUMNS", columns.to_string());
+        self.set_variable("LINES", lines.to_string());
+    }
 
-            let mut command = Command::new(path);
-            command.arg0(cmd).args(args);
+    /// The `REPORTTIME` threshold, or `None` when unset or not a number of
+    /// seconds.
+    fn report_time(&self) -> Option<Duration> {
+        let seconds = self.lookup_variable("REPORTTIME")?.parse::<f64>().ok()?;
+        Duration::try_from_secs_f64(seconds).ok()
+    }
 
-            // Setup stdout redirection if specified
-            if let Some(ref file_path) = stdout_file {
-                let file_result = if stdout_append {
-                    std::fs::OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(file_path)
-                } else {
-                    File::create(file_path)
-                };
+    /// Assign a variable, keeping it exported if it already was.
+    fn set_variable(&mut self, name: &str, value: String) {
+        if env::var_os(name).is_some() {
+            env::set_var(name, value);
+        } else {
+            self.variables.insert(name.to_string(), value);
+        }
+    }
 
-                match file_result {
-                    Ok(file) => {
-                        command.stdout(Stdio::from(file));
-                    }
-                    Err(_) => {
-                        eprintln!("Failed to create file: {}", file_path);
-                        continue;
-                    }
+    /// Add every non-empty line of `file` to the history.
+    fn read_history(&mut self, file: File) {
+        let reader = BufReader::new(file);
+        for cmd in reader.lines().map_while(Result::ok) {
+            // Skip empty lines
+            if !cmd.trim().is_empty() {
+                self.command_history.push(cmd.clone());
+                self.editor.add_history_entry(&cmd).ok();
+            }
+        }
+    }
+
+    fn save_history_to_file(&self) {
+        if let Some(histfile) = history_file() {
+            if let Ok(mut file) = File::create(&histfile) {
+                for cmd in &self.command_history {
+                    writeln!(file, "{}", cmd).ok();
                 }
             }
+        }
+    }
 
-            // Setup stderr redirection if specified
-            if let Some(ref file_path) = stderr_file {
-                let file_result = if stderr_append {
-                    std::fs::OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(file_path)
-                } else {
-                    File::create(file_path)
-                };
+    /// Value of a shell variable, falling back to the environment. `?` is
+    /// the status of the last command.
+    fn lookup_variable(&self, name: &str) -> Option<String> {
+        if name == "?" {
+            return Some(self.last_status.to_string());
+        }
+        if let Some(target) = name.strip_prefix('!') {
+            return self.lookup_indirect(target);
+        }
+        self.variables
+            .get(name)
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
 
-                match file_result {
-                    Ok(file) => {
-                        command.stderr(Stdio::from(file));
-                    }
-                    Err(_) => {
-                        eprintln!("Failed to create file: {}", file_path);
-                        continue;
+    /// `${!PREFIX*}` (or `@`) is the sorted names of the variables that start
+    /// with PREFIX; `${!NAME}` is the value of the variable NAME names.
+    fn lookup_indirect(&self, target: &str) -> Option<String> {
+        if let Some(prefix) = target.strip_suffix(['*', '@']) {
+            let mut names: Vec<String> = env::vars()
+                .map(|(name, _)| name)
+                .chain(self.variables.keys().cloned())
+                .filter(|name| name.starts_with(prefix))
+                .collect();
+            names.sort();
+            return Some(names.join(" "));
+        }
+        let name = self.lookup_variable(target)?;
+        if !is_valid_name(&name) && name != "?" {
+            return None;
+        }
+        self.lookup_variable(&name)
+    }
+
+    /// The prompt held in the variable `name` with its escapes expanded, or
+    /// `default` when it's unset. Rebuilt for every prompt so `\w` follows
+    /// `cd`.
+    fn prompt_for(&self, name: &str, default: &str) -> String {
+        match self.lookup_variable(name) {
+            Some(template) => {
+                let cwd = logical_cwd().unwrap_or_default();
+                expand_prompt(&template, &cwd, self.lookup_variable("HOME").as_deref())
+            }
+            None => default.to_string(),
+        }
+    }
+
+    /// Complete a line that continues onto the next one, prompting with
+    /// `PS2` (default `> `) for each extra line.
+    fn read_continuation(&mut self, mut line: String) -> Result<String, ShellError> {
+        while let Some(pending) = pending_continuation(&line) {
+            let prompt = self.prompt_for("PS2", "> ");
+            let next = match self.editor.readline(&prompt) {
+                Ok(next) => next,
+                Err(_) => match pending {
+                    // A dangling backslash at EOF is simply dropped
+                    Continuation::Backslash => {
+                        line.pop();
+                        break;
                     }
+                    Continuation::Quote(quote) => return Err(ShellError::UnexpectedEof(quote)),
+                },
+            };
+
+            match pending {
+                // Backslash-newline joins the lines
+                Continuation::Backslash => {
+                    line.pop();
                 }
+                // A newline inside quotes is part of the word
+                Continuation::Quote(_) => line.push('\n'),
             }
+            line.push_str(&next);
+        }
+        Ok(line)
+    }
 
-            let output = command.output();
+    /// Collect background jobs that have finished, so they don't linger as
+    /// zombies. An interactive shell reports them as done right away; a
+    /// script keeps them for `jobs` to report.
+    fn reap_jobs(&mut self) {
+        for job in &mut self.jobs {
+            job.child.try_wait().ok();
+        }
+        if io::stdin().is_terminal() {
+            let report = self.job_report(false);
+            self.out.write_all(&report).ok();
+        }
+    }
 
-            match output {
-                Ok(output) => {
-                    if stdout_file.is_none() {
-                        io::stdout().write_all(&output.stdout).unwrap();
-                    }
-                    if stderr_file.is_none() {
-                        io::stderr().write_all(&output.stderr).unwrap();
-                    }
+    /// Poll every job and describe it in bash's format, `+` marking the
+    /// current (newest) job and `-` the one before. Finished jobs are
+    /// described once, then dropped. With `running`, running jobs are
+    /// listed too.
+    fn job_report(&mut self, running: bool) -> Vec<u8> {
+        let mut output = Vec::new();
+        let count = self.jobs.len();
+        for (index, job) in self.jobs.iter_mut().enumerate() {
+            let mark = match count - index {
+                1 => '+',
+                2 => '-',
+                _ => ' ',
+            };
+            match job.child.try_wait() {
+                Ok(None) if running => {
+                    let state = "Running";
+                    writeln!(
+                        output,
+                        "[{}]{}  {:<24}{} &",
+                        job.id, mark, state, job.command
+                    )
+                    .ok();
                 }
-                Err(_) => {
-                    println!("{}: command not found", input);
+                Ok(None) => {}
+                _ => {
+                    writeln!(
+                        output,
+                        "[{}]{}  {:<24}{}",
+                        job.id, mark, "Done", job.command
+                    )
+                    .ok();
                 }
             }
-        } else {
-            println!("{}: command not found", input);
         }
+        self.jobs
+            .retain_mut(|job| matches!(job.child.try_wait(), Ok(None)));
+        output
     }
-}
 
-fn find_in_path(cmd: &str) -> Option<String> {
-    let path_env = env::var("PATH").ok()?;
+    /// Hand the job table to the completer, for `fg %N` and the like.
+    fn share_jobs(&mut self) {
+        let jobs = self
+            .jobs
+            .iter()
+            .map(|job| (job.id, job.command.clone()))
+            .collect();
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.jobs = jobs;
+        }
+    }
+
+    /// Whether any background job is still running.
+    fn has_running_jobs(&mut self) -> bool {
+        self.jobs
+            .iter_mut()
+            .any(|job| matches!(job.child.try_wait(), Ok(None)))
+    }
+
+    /// Save history, flush pending output and leave with `code`.
+    fn exit(&mut self, code: i32) -> ! {
+        self.save_history_to_file();
+        self.out.flush().ok();
+        std::process::exit(code);
+    }
+
+    /// Run one command line, returning the exit status of what it ran.
+    /// Commands separated by `;`, `&&` and `||` run left to right, each one
+    /// only if the status so far allows it.
+    fn run_line(&mut self, input: &str) -> Result<i32, ShellError> {
+        let mut result: Result<i32, ShellError> = Ok(self.last_status);
 
-    for dir in path_env.split(':') {
-        let full_path = Path::new(dir).join(cmd);
+        for (index, (connector, command)) in split_list(input)?.into_iter().enumerate() {
+            if index > 0 {
+                // Report a failure before moving on, so `$?` sees its status
+                let status = match result {
+                    Ok(status) => status,
+                    Err(err) => {
+                        self.out.flush().ok();
+                        eprintln!("{}", err);
+                        err.exit_code()
+                    }
+                };
+                self.last_status = status;
+                result = Ok(status);
 
-        if full_path.exists() {
-            if let Ok(metadata) = std::fs::metadata(&full_path) {
-                let permissions = metadata.permissions();
-                // Check if file has execute permission (user, group, or other)
-                if permissions.mode() & 0o111 != 0 {
-                    return full_path.to_str().map(|s| s.to_string());
+                let run = match connector {
+                    Some(Connector::And) => status == 0,
+                    Some(Connector::Or) => status != 0,
+                    Some(Connector::Then) | None => true,
+                };
+                if !run {
+                    // Its here-documents were read with the line all the same
+                    let skipped = here_doc_specs(command).len().min(self.here_docs.len());
+                    self.here_docs.drain(..skipped);
+                    continue;
                 }
             }
+            let cwd = env::current_dir().unwrap_or_default();
+            let started = Instant::now();
+            result = self.run_command(command);
+            self.trace(command, &cwd, &result, started.elapsed());
+            if self.substitution_exit || self.returning {
+                break;
+            }
         }
+
+        result
     }
 
-    None
-}
+    /// Append a JSON line describing a finished command to
+    /// `POPPER_TRACE_FILE`, when set. Tracing is best effort: a log that
+    /// can't be written never affects the command.
+    fn trace(
+        &self,
+        command: &str,
+        cwd: &Path,
+        result: &Result<i32, ShellError>,
+        elapsed: Duration,
+    ) {
+        let Some(path) = self.lookup_variable("POPPER_TRACE_FILE") else {
+            return;
+        };
+        if path.is_empty() {
+            return;
+        }
+        let mut words = parse_arguments(command).into_iter();
+        let entry = serde_json::json!({
+            "command": words.next().unwrap_or_default(),
+            "args": words.collect::<Vec<_>>(),
+            "cwd": cwd.to_string_lossy(),
+            "status": match result {
+                Ok(status) => *status,
+                Err(err) => err.exit_code(),
+            },
+            "duration_ms": elapsed.as_secs_f64() * 1000.0,
+        });
+        if let Ok(mut file) = File::options().create(true).append(true).open(path) {
+            writeln!(file, "{}", entry).ok();
+        }
+    }
 
-fn parse_arguments(input: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current_arg = String::new();
-    let mut in_single_quote = false;
-    let mut in_double_quote = false;
-    let mut chars = input.chars().peekable();
+    /// Run a single command or pipeline. A trailing `&` starts an external
+    /// command in the background; builtins and pipelines still run in the
+    /// foreground.
+    fn run_command(&mut self, input: &str) -> Result<i32, ShellError> {
+        let exit_warned = std::mem::take(&mut self.exit_warned);
+        let (input, background) = take_background(input);
+        let background = background.then_some(input);
+        let expanded = self.expand_alias(input);
+        if let Some(assignments) = assignment_words(&expanded) {
+            return self.run_assignments(&assignments);
+        }
+        let expanded = self.substitute_commands(&expanded, true)?;
+        let input = expanded.as_str();
 
-    while let Some(ch) = chars.next() {
-        match ch {
-            '\\' if !in_single_quote => {
+        // Parse input first to check for pipelines, expanding variables
+        let unbound = RefCell::new(None);
+        let parts = expand_command(input, &|name| self.lookup_checked(name, &unbound));
+        self.check_unbound(unbound.into_inner())?;
+        let parts = self.attach_here_docs(parts);
+        if parts.is_empty() {
+            // A line of nothing but substitutions reports their status
+            return Ok(self.last_status);
+        }
+
+        if self.restricted {
+            check_restricted(&parts)?;
+        }
+
+        // Check for pipeline first (before handling built-ins)
+        if parts.iter().any(|p| p.is_operator("|")) {
+            let listings: Vec<_> = parts
+                .split(|part| part.is_operator("|"))
+                .map(|stage| self.stage_listing(&parse_redirection(stage).cmd_parts))
+                .collect();
+            return execute_pipeline(&parts, &mut self.out, &listings);
+        }
+
+        // `builtin NAME args...` dispatches straight to the named builtin
+        let (input, parts) = if parts[0].text() == "builtin" {
+            let Some(name) = parts.get(1).map(Arg::text) else {
+                return Ok(0);
+            };
+            if !is_builtin(name) {
+                return Err(ShellError::builtin(
+                    "builtin",
+                    format!("{}: not a shell builtin", name),
+                ));
+            }
+            let rest = input["builtin".len()..].trim_start();
+            (rest, parts[1..].to_vec())
+        } else {
+            (input, parts)
+        };
+
+        // A redirection operator must be followed by its target
+        if let Some(Arg::Operator(last)) = parts.last() {
+            if matches!(
+                last.as_str(),
+                "<" | "<<" | "<<-" | "<<<" | ">" | ">>" | "1>" | "1>>" | "2>" | "2>>"
+            ) {
+                return Err(ShellError::SyntaxError("newline".to_string()));
+            }
+        }
+        let words = arg_texts(&parts);
+
+        // Now handle built-in commands that don't involve pipelines
+        if words[0] == "exit" {
+            // Running jobs hold up the first `exit`; a second one in a row,
+            // or `exit -f`, leaves anyway
+            let force = words.get(1).is_some_and(|arg| arg == "-f");
+            if !force && !exit_warned && self.substitution_depth == 0 && self.has_running_jobs() {
+                self.exit_warned = true;
+                self.out.flush().ok();
+                eprintln!("There are running jobs.");
+                return Ok(1);
+            }
+            // Like POSIX shells, the status wraps into 0-255 and defaults to $?
+            let exit_code = match words.get(1 + usize::from(force)) {
+                Some(code) => match code.parse::<i64>() {
+                    Ok(code) => code.rem_euclid(256) as i32,
+                    Err(_) => report(ShellError::NumericArgumentRequired {
+                        builtin: "exit".to_string(),
+                        arg: code.clone(),
+                    }),
+                },
+                None => self.last_status,
+            };
+            // Inside `$(...)` exit only ends the substitution
+            if self.substitution_depth > 0 {
+                self.substitution_exit = true;
+                return Ok(exit_code);
+            }
+            self.exit(exit_code);
+        }
+
+        if words[0] == "return" {
+            return self.run_return(&words[1..]);
+        }
+
+        if words[0] == "echo" {
+            let (echo_parts, close_stdout, _close_stderr) = take_fd_closes(&parts[1..]); // Skip "echo" itself
+            let Redirections {
+                cmd_parts: cmd_args,
+                stdin_file,
+                stdout_file,
+                stdout_append,
+                stderr_file,
+                stderr_append,
+                stdout_to_stderr,
+                ..
+            } = parse_redirection(&echo_parts);
+
+            let output_text = echo_output(&cmd_args);
+
+            // echo ignores its input, but the file must still exist
+            if let Some(file_path) = stdin_file {
+                open_input(&file_path)?;
+            }
+
+            // Create stderr file even if empty (echo doesn't write to stderr)
+            if let Some(file_path) = &stderr_file {
+                if stdout_file.as_ref() != Some(file_path) {
+                    open_redirect(file_path, stderr_append)?;
+                }
+            }
+
+            if let Some(file_path) = stdout_file {
+                // Redirect stdout to file
+                let mut file = open_redirect(&file_path, stdout_append)?;
+                file.write_all(&output_text).ok();
+            } else if close_stdout {
+                return Err(ShellError::builtin(
+                    "echo",
+                    "write error: Bad file descriptor",
+                ));
+            } else if stdout_to_stderr {
+                // `1>&2`: keep the order with what was already printed
+                self.out.flush().ok();
+                io::stderr().write_all(&output_text).ok();
+            } else {
+                // Print to stdout
+                self.out.write_all(&output_text).ok();
+            }
+
+            return Ok(0);
+        }
+
+        if words[0] == "pwd" {
+            let path = pwd_path(&words[1..])?;
+            writeln!(self.out, "{}", path.display()).ok();
+            return Ok(0);
+        }
+
+        if words[0] == "cd" {
+            return self.run_cd(&words[1..]);
+        }
+
+        if words[0] == "repeat" {
+            return self.run_repeat(input, &words);
+        }
+
+        if words[0] == "loop" {
+            return self.run_loop(input, &words);
+        }
+
+        if words[0] == "export" {
+            return self.run_export(&words[1..]);
+        }
+
+        if words[0] == "unset" {
+            return Ok(self.run_unset(&words[1..]));
+        }
+
+        if words[0] == "set" {
+            return self.run_set(&words[1..]);
+        }
+
+        if words[0] == "fg" {
+            return self.run_fg(&words[1..]);
+        }
+
+        if words[0] == "env" {
+            return self.run_env(&parts, background);
+        }
+
+        if words[0] == "jobs" {
+            let report = self.job_report(true);
+            self.out.write_all(&report).ok();
+            return Ok(0);
+        }
+
+        if words[0] == "alias" {
+            return Ok(self.run_alias(&words[1..]));
+        }
+
+        if words[0] == "unalias" {
+            return Ok(self.run_unalias(&words[1..]));
+        }
+
+        if words[0] == "source" || words[0] == "." {
+            return self.run_source(&words[0], &words[1..]);
+        }
+
+        if words[0] == "compgen" {
+            let matches = compgen_matches(&words[1..])?;
+            for candidate in &matches {
+                writeln!(self.out, "{}", candidate).ok();
+            }
+            // Like bash, finding nothing is a failure
+            return Ok(if matches.is_empty() { 1 } else { 0 });
+        }
+
+        if matches!(
+            words[0].as_str(),
+            "kill" | "grep" | "wc" | "which" | "true" | "false" | "test" | "["
+        ) {
+            return self.run_filter(&parts);
+        }
+
+        // Only built with the `ls` feature, so it never shadows the real one
+        if cfg!(feature = "ls") && words[0] == "ls" {
+            return self.run_filter(&parts);
+        }
+
+        if words[0] == "type" && words.len() > 1 {
+            let mut status = 0;
+            for cmd in &words[1..] {
+                if is_builtin(cmd) {
+                    writeln!(self.out, "{} is a shell builtin", cmd).ok();
+                } else if let Some(path) = find_in_path(cmd) {
+                    // Search for executable in PATH
+                    writeln!(self.out, "{} is {}", cmd, path).ok();
+                } else {
+                    writeln!(self.out, "{}: not found", cmd).ok();
+                    status = 1;
+                }
+            }
+            return Ok(status);
+        }
+
+        if words[0] == "history" {
+            return self.run_history(&words[1..]);
+        }
+
+        // Try to execute as external program
+        self.run_external(&parts, &[], background)
+    }
+
+    /// Replace the delimiter of each `<< WORD` (or `<<- WORD`) in `parts`
+    /// with the body of the next pending here-document, expanded unless
+    /// WORD was quoted, leaving `<<` and the body for `parse_redirection`.
+    fn attach_here_docs(&mut self, parts: Vec<Arg>) -> Vec<Arg> {
+        let mut attached = Vec::new();
+        let mut parts = parts.into_iter();
+        while let Some(part) = parts.next() {
+            if !part.is_operator("<<") && !part.is_operator("<<-") {
+                attached.push(part);
+                continue;
+            }
+            // Without a delimiter the operator stays for the syntax check
+            if parts.next().is_none() {
+                attached.push(part);
+                break;
+            }
+            let body = match self.here_docs.pop_front() {
+                Some(doc) if doc.expand => self.expand_here_doc(&doc.body),
+                Some(doc) => doc.body,
+                None => String::new(),
+            };
+            attached.push(Arg::Operator("<<".to_string()));
+            attached.push(Arg::Word(body));
+        }
+        attached
+    }
+
+    /// Expand a here-document body: `$NAME`, `${NAME}` and `$(command)` are
+    /// substituted, a backslash escapes `$`, `` ` `` and itself, and
+    /// backslash-newline joins lines. Quotes are ordinary characters.
+    fn expand_here_doc(&mut self, body: &str) -> String {
+        let mut expanded = String::new();
+        let mut chars = body.char_indices().peekable();
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '\\' => match chars.peek() {
+                    Some((_, '$' | '`' | '\\')) => expanded.extend(chars.next().map(|(_, ch)| ch)),
+                    Some((_, '\n')) => {
+                        chars.next();
+                    }
+                    _ => expanded.push(ch),
+                },
+                '$' if body[i + 1..].starts_with('(') => {
+                    let start = i + 2;
+                    let Some(len) = substitution_end(&body[start..]) else {
+                        // Never closed: the rest is kept as typed
+                        expanded.push_str(&body[i..]);
+                        break;
+                    };
+                    while chars.next_if(|&(j, _)| j <= start + len).is_some() {}
+                    let output = self.capture_output(&body[start..start + len]);
+                    expanded.push_str(output.unwrap_or_default().trim_end_matches('\n'));
+                }
+                '$' => match read_parameter(&mut body[i + 1..].chars().peekable()) {
+                    Some(name) => {
+                        expanded.push_str(&self.lookup_variable(&name).unwrap_or_default());
+                        // Skip the name, and its braces if it had them
+                        let braces = if body[i + 1..].starts_with('{') { 2 } else { 0 };
+                        let end = i + name.len() + braces;
+                        while chars.next_if(|&(j, _)| j <= end).is_some() {}
+                    }
+                    None => expanded.push(ch),
+                },
+                _ => expanded.push(ch),
+            }
+        }
+        expanded
+    }
+
+    /// `NAME=value...` on its own sets shell variables, keeping exported
+    /// ones exported. A value is expanded but never split into words or
+    /// globbed, so `out=$(cmd)` holds all of cmd's output. The status is
+    /// that of the last command substitution, or 0.
+    fn run_assignments(&mut self, assignments: &[(&str, &str)]) -> Result<i32, ShellError> {
+        if self.restricted {
+            if let Some((name, _)) = assignments
+                .iter()
+                .find(|(name, _)| *name == "PATH" || *name == "SHELL")
+            {
+                return Err(ShellError::Restricted(format!("set {}", name)));
+            }
+        }
+
+        let mut status = 0;
+        for (name, value) in assignments {
+            let value = if value.contains("$(") {
+                let value = self.substitute_commands(value, false)?;
+                status = self.last_status;
+                value
+            } else {
+                value.to_string()
+            };
+            let unbound = RefCell::new(None);
+            let words = split_words(&value, Some(&|name| self.lookup_checked(name, &unbound)));
+            self.check_unbound(unbound.into_inner())?;
+            let value: Vec<String> = words.into_iter().map(|word| word.text).collect();
+            self.set_variable(name, value.concat());
+        }
+        Ok(status)
+    }
+
+    /// Look up a variable for expansion, noting the first unset one in
+    /// `unbound` under `set -u`.
+    fn lookup_checked(&self, name: &str, unbound: &RefCell<Option<String>>) -> Option<String> {
+        let value = self.lookup_variable(name);
+        if value.is_none() && self.nounset {
+            unbound.borrow_mut().get_or_insert_with(|| name.to_string());
+        }
+        value
+    }
+
+    /// Fail on the unbound variable `lookup_checked` found, if any.
+    fn check_unbound(&mut self, unbound: Option<String>) -> Result<(), ShellError> {
+        let Some(name) = unbound else {
+            return Ok(());
+        };
+        let err = ShellError::UnboundVariable(name);
+        // A script stops at its first unbound variable
+        if self.substitution_depth == 0 && !io::stdin().is_terminal() {
+            self.out.flush().ok();
+            eprintln!("{}", err);
+            self.exit(err.exit_code());
+        }
+        Err(err)
+    }
+
+    /// Run a line and record its status, reporting a failure on stderr.
+    fn execute(&mut self, line: &str) {
+        self.last_status = match self.run_line(line) {
+            Ok(status) => status,
+            Err(err) => {
+                self.out.flush().ok();
+                eprintln!("{}", err);
+                err.exit_code()
+            }
+        };
+    }
+
+    /// Run a builtin that only reads and writes streams (`kill`, `grep`,
+    /// `wc`, `which`, `true`, `false`, `test`, listing `env`) outside a
+    /// pipeline, honouring its redirections the way a child's would be.
+    fn run_filter(&mut self, parts: &[Arg]) -> Result<i32, ShellError> {
+        let (parts, close_stdout, close_stderr) = take_fd_closes(parts);
+        let redirections = parse_redirection(&parts);
+        let fail = |err| spawn_error(parts[0].text(), err);
+        let stdin = match (&redirections.stdin_file, redirections.stdin_data) {
+            (Some(path), _) => Some(open_input(path)?),
+            (None, Some(data)) => Some(data_reader(data).map_err(fail)?),
+            (None, None) => None,
+        };
+
+        // Open the targets up front, like a child's would be; `> log 2>&1`
+        // shares one open file
+        let stdout_target = match &redirections.stdout_file {
+            Some(path) => Some(open_redirect(path, redirections.stdout_append)?),
+            None => None,
+        };
+        let stderr_target = match &redirections.stderr_file {
+            Some(path) if redirections.stdout_file.as_ref() == Some(path) => stdout_target
+                .as_ref()
+                .map(File::try_clone)
+                .transpose()
+                .map_err(fail)?,
+            Some(path) => Some(open_redirect(path, redirections.stderr_append)?),
+            // A closed stderr just loses what the builtin would have said
+            None if close_stderr => Some(open_redirect(NULL_DEVICE, false)?),
+            None if redirections.stderr_to_stdout => {
+                self.out.flush().ok();
+                let stdout_copy = io::stdout().as_fd().try_clone_to_owned().map_err(fail)?;
+                Some(File::from(stdout_copy))
+            }
+            None => None,
+        };
+
+        let Some((name, args)) = redirections.cmd_parts.split_first() else {
+            return Ok(0);
+        };
+        // Lay an `ls` listing out in columns when it goes to the terminal
+        #[cfg(feature = "ls")]
+        let width = (name == "ls"
+            && stdout_target.is_none()
+            && !redirections.stdout_to_stderr
+            && io::stdout().is_terminal())
+        .then(|| {
+            self.lookup_variable("COLUMNS")
+                .and_then(|columns| columns.parse().ok())
+                .unwrap_or(80)
+        });
+        let (output, status) = with_stderr(stderr_target.as_ref(), || match name.as_str() {
+            #[cfg(feature = "ls")]
+            "ls" => ls::ls_output(args, width),
+            _ => execute_builtin(name, args, stdin),
+        })
+        .map_err(fail)?;
+
+        if let Some(mut file) = stdout_target {
+            file.write_all(&output).ok();
+        } else if close_stdout {
+            if !output.is_empty() {
+                return Err(ShellError::builtin(
+                    name,
+                    "write error: Bad file descriptor",
+                ));
+            }
+        } else if redirections.stdout_to_stderr {
+            self.out.flush().ok();
+            io::stderr().write_all(&output).ok();
+        } else {
+            self.out.write_all(&output).ok();
+        }
+        Ok(status)
+    }
+
+    /// `env [NAME=value...] [command args...]` runs the command with the
+    /// assignments added to its environment, leaving the shell's alone.
+    /// Without a command it lists the environment, assignments included.
+    fn run_env(&mut self, parts: &[Arg], background: Option<&str>) -> Result<i32, ShellError> {
+        let (assignments, _) = split_env_assignments(&arg_texts(&parts[1..]));
+        let command = &parts[1 + assignments.len()..];
+        if parse_redirection(command).cmd_parts.is_empty() {
+            return self.run_filter(parts);
+        }
+        self.run_external(command, &assignments, background)
+    }
+
+    /// `fg [%N]` waits for job N (by default the newest one), echoing its
+    /// command line, and returns its exit status.
+    fn run_fg(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        let index = match args.first().map(String::as_str) {
+            None | Some("%%" | "%+" | "%") => self.jobs.len().checked_sub(1),
+            Some("%-") => self.jobs.len().checked_sub(2),
+            Some(spec) => spec
+                .strip_prefix('%')
+                .unwrap_or(spec)
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| self.jobs.iter().position(|job| job.id == id)),
+        };
+        let Some(index) = index else {
+            return Err(ShellError::builtin("fg", "no such job"));
+        };
+
+        let mut job = self.jobs.remove(index);
+        writeln!(self.out, "{}", job.command).ok();
+        self.out.flush().ok();
+
+        // The job's process group gets the terminal, and with it Ctrl-C,
+        // until it finishes
+        let pid = job.child.id() as i32;
+        let on_terminal = io::stdin().is_terminal();
+        if on_terminal {
+            hand_terminal_to(Pid::from_raw(pid));
+        }
+        FOREGROUND_CHILD.store(pid, Ordering::Relaxed);
+        let status = job.child.wait();
+        FOREGROUND_CHILD.store(0, Ordering::Relaxed);
+        if on_terminal {
+            hand_terminal_to(nix::unistd::getpgrp());
+        }
+        let status = status.map_err(|err| ShellError::builtin("fg", err.to_string()))?;
+        Ok(exit_status_code(status))
+    }
+
+    /// `set -u` (or `set -o nounset`) makes expanding an unset variable an
+    /// error; `set +u` turns that off again. Bare `set` lists every variable.
+    fn run_set(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        if args.is_empty() {
+            self.out.write_all(&set_listing(&self.variables)).ok();
+            return Ok(0);
+        }
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let enable = match arg.as_str() {
+                "-u" => true,
+                "+u" => false,
+                "-o" | "+o" => {
+                    match args.next().map(String::as_str) {
+                        Some("nounset") => {}
+                        Some(option) => {
+                            return Err(ShellError::builtin(
+                                "set",
+                                format!("{}: invalid option name", option),
+                            ))
+                        }
+                        None => {
+                            return Err(ShellError::builtin(
+                                "set",
+                                format!("{}: option requires an argument", arg),
+                            ))
+                        }
+                    }
+                    arg == "-o"
+                }
+                _ => {
+                    return Err(ShellError::builtin(
+                        "set",
+                        format!("{}: invalid option", arg),
+                    ))
+                }
+            };
+            self.nounset = enable;
+        }
+        Ok(0)
+    }
+
+    /// `source FILE` (or `. FILE`) runs FILE's lines in this shell, so
+    /// `cd`, `export` and aliases stick. Its status is the last command's.
+    fn run_source(&mut self, name: &str, args: &[String]) -> Result<i32, ShellError> {
+        let Some(path) = args.first() else {
+            return Err(ShellError::builtin(name, "filename argument required"));
+        };
+        if !self.source_file(path) {
+            return Err(ShellError::builtin(
+                &format!("popper: {}", name),
+                format!("{}: No such file or directory", path),
+            ));
+        }
+        Ok(self.last_status)
+    }
+
+    /// `return [N]` stops the file being sourced, which then reports status
+    /// N (by default `$?`). Outside `source` it's an error.
+    fn run_return(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        if self.source_depth == 0 {
+            return Err(ShellError::builtin(
+                "return",
+                "can only `return' from a sourced script",
+            ));
+        }
+        // Like `exit`, the status wraps into 0-255
+        let status = match args.first() {
+            Some(code) => match code.parse::<i64>() {
+                Ok(code) => code.rem_euclid(256) as i32,
+                Err(_) => report(ShellError::NumericArgumentRequired {
+                    builtin: "return".to_string(),
+                    arg: code.clone(),
+                }),
+            },
+            None => self.last_status,
+        };
+        self.returning = true;
+        Ok(status)
+    }
+
+    /// Run each line of the file at `path`, joining continued lines and
+    /// skipping comment lines, until one runs `return`. Returns `false` if
+    /// it can't be read.
+    fn source_file(&mut self, path: &str) -> bool {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return false;
+        };
+        self.source_depth += 1;
+        // The file's here-documents are its own
+        let outer_here_docs = std::mem::take(&mut self.here_docs);
+
+        let mut pending = String::new();
+        let mut lines = contents.lines();
+        while let Some(line) = lines.next() {
+            pending.push_str(line);
+            match pending_continuation(&pending) {
+                // Backslash-newline joins the lines
+                Some(Continuation::Backslash) => {
+                    pending.pop();
+                    continue;
+                }
+                // A newline inside quotes is part of the word
+                Some(Continuation::Quote(_)) => {
+                    pending.push('\n');
+                    continue;
+                }
+                None => {}
+            }
+
+            let command = std::mem::take(&mut pending);
+            let command = command.trim();
+            if !command.is_empty() && !command.starts_with('#') {
+                self.here_docs = collect_here_docs(command, || lines.next().map(str::to_string));
+                self.execute(command);
+            }
+            if self.returning {
+                break;
+            }
+        }
+
+        // Whatever is left ends inside quotes, or after a dropped trailing backslash
+        match pending_continuation(&pending) {
+            Some(Continuation::Quote(quote)) => {
+                let err = ShellError::UnexpectedEof(quote);
+                eprintln!("{}", err);
+                self.last_status = err.exit_code();
+            }
+            _ if !pending.trim().is_empty() => self.execute(pending.trim()),
+            _ => {}
+        }
+        self.source_depth -= 1;
+        self.returning = false;
+        self.here_docs = outer_here_docs;
+        true
+    }
+
+    /// `cd [-L|-P] [--] [DIR]` changes directory. `cd -` returns to the
+    /// previous one, and without DIR it goes to `HOME`. A relative DIR may
+    /// also be found under `CDPATH`. `cd -` and `CDPATH` hits print the
+    /// directory they land in, like bash.
+    fn run_cd(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        let (operand, physical) = cd_operand(args)?;
+        let (target, announce) = match operand {
+            Some("-") => match &self.previous_dir {
+                Some(previous) => (previous.clone(), true),
+                None => return Err(ShellError::builtin("cd", "OLDPWD not set")),
+            },
+            Some(path) => match resolve_in_cdpath(path) {
+                Some(found) => (found, true),
+                None => (PathBuf::from(path), false),
+            },
+            // Without a directory, cd goes to HOME
+            None => match env::var("HOME") {
+                Ok(home) => (PathBuf::from(home), false),
+                Err(_) => return Err(ShellError::builtin("cd", "HOME not set")),
+            },
+        };
+
+        let previous = logical_cwd().ok();
+        let not_found = || {
+            ShellError::builtin(
+                "cd",
+                format!("{}: No such file or directory", target.display()),
+            )
+        };
+        // `-L` takes `..` off the path it came by, symlinks and all; if that
+        // path is gone, the kernel's own view of the target still works
+        let logical = previous
+            .as_deref()
+            .filter(|_| !physical)
+            .map(|previous| logical_join(previous, &target))
+            .filter(|logical| env::set_current_dir(logical).is_ok());
+        let current = match logical {
+            Some(logical) => logical,
+            None => {
+                env::set_current_dir(&target).map_err(|_| not_found())?;
+                env::current_dir().map_err(|_| not_found())?
+            }
+        };
+
+        // Keep OLDPWD and PWD current for the commands we run
+        if let Some(previous) = previous {
+            env::set_var("OLDPWD", &previous);
+            self.previous_dir = Some(previous);
+        }
+        env::set_var("PWD", &current);
+        if announce {
+            writeln!(self.out, "{}", current.display()).ok();
+        }
+        Ok(0)
+    }
+
+    /// `repeat N cmd...` runs the rest of the line N times, reporting the
+    /// status of the last run.
+    fn run_repeat(&mut self, input: &str, parts: &[String]) -> Result<i32, ShellError> {
+        let Some(count_word) = parts.get(1) else {
+            return Err(ShellError::builtin("repeat", "usage: repeat count command"));
+        };
+        let count = count_word
+            .parse::<usize>()
+            .map_err(|_| ShellError::builtin("repeat", format!("{}: invalid count", count_word)))?;
+
+        let command = skip_words(input, 2);
+
+        // A failing run is reported like any command line and the rest
+        // still happen, each seeing the status of the one before
+        let mut status = 0;
+        for _ in 0..count {
+            self.execute(command);
+            status = self.last_status;
+            if self.returning {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    /// `loop [-n SECONDS] cmd...` clears the screen and re-runs the command
+    /// every SECONDS (default 2) until interrupted with Ctrl-C, like `watch`.
+    fn run_loop(&mut self, input: &str, parts: &[String]) -> Result<i32, ShellError> {
+        let (interval, consumed) = parse_loop_args(parts)?;
+        let command = skip_words(input, consumed);
+
+        // Ctrl-C only ends the loop; the shell itself keeps running
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let sigint = signal_hook::flag::register(signal_hook::consts::SIGINT, interrupted.clone())
+            .map_err(|err| ShellError::builtin("loop", err.to_string()))?;
+
+        while !interrupted.load(Ordering::Relaxed) {
+            // Clear the screen and home the cursor
+            write!(self.out, "\x1b[H\x1b[2J").ok();
+            if let Err(err) = self.run_line(command) {
+                self.out.flush().ok();
+                eprintln!("{}", err);
+            }
+            self.out.flush().ok();
+
+            // Sleep in short slices so Ctrl-C is noticed promptly
+            let deadline = Instant::now() + interval;
+            while !interrupted.load(Ordering::Relaxed) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50).min(interval));
+            }
+        }
+
+        signal_hook::low_level::unregister(sigint);
+        Ok(130)
+    }
+
+    /// `export NAME=value` sets an environment variable inherited by every
+    /// command spawned afterwards, `export NAME` exports an existing shell
+    /// variable, `export -n NAME` turns one back into a shell variable, and
+    /// bare `export` lists them.
+    fn run_export(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        let (unexport, args) = match args.split_first() {
+            Some((flag, rest)) if flag == "-n" => (true, rest),
+            _ => (false, args),
+        };
+
+        if args.is_empty() && !unexport {
+            self.out.write_all(&export_listing()).ok();
+            return Ok(0);
+        }
+
+        let mut status = 0;
+        for arg in args {
+            // Only the first `=` separates the name; the value may contain more
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+
+            if !is_valid_name(name) {
+                status = report(ShellError::InvalidIdentifier {
+                    builtin: "export".to_string(),
+                    name: arg.clone(),
+                });
+                continue;
+            }
+
+            if unexport {
+                // Keep the value, but stop passing it to child processes
+                let value = value.or_else(|| env::var(name).ok());
+                env::remove_var(name);
+                if let Some(value) = value {
+                    self.variables.insert(name.to_string(), value);
+                }
+            } else if let Some(value) = value.or_else(|| self.variables.remove(name)) {
+                self.variables.remove(name);
+                env::set_var(name, value);
+            }
+        }
+        Ok(status)
+    }
+
+    /// `unset NAME...` removes shell and environment variables; names that
+    /// aren't set are ignored.
+    fn run_unset(&mut self, names: &[String]) -> i32 {
+        let mut status = 0;
+        for name in names {
+            if is_valid_name(name) {
+                self.variables.remove(name);
+                env::remove_var(name);
+            } else {
+                status = report(ShellError::InvalidIdentifier {
+                    builtin: "unset".to_string(),
+                    name: name.clone(),
+                });
+            }
+        }
+        status
+    }
+
+    /// Replace each `$(command)` outside single quotes with the output of
+    /// running `command`, minus trailing newlines. The output is escaped
+    /// so the lexer reads it back literally: inside double quotes it stays
+    /// one word, unquoted it splits at whitespace unless `split` is false.
+    /// `$?` becomes the status of the last substitution.
+    fn substitute_commands(&mut self, input: &str, split: bool) -> Result<String, ShellError> {
+        if !input.contains("$(") {
+            return Ok(input.to_string());
+        }
+
+        let mut result = String::new();
+        let mut in_single_quote = false;
+        let mut in_double_quote = false;
+        let mut chars = input.char_indices().peekable();
+
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '\\' if !in_single_quote => {
+                    result.push(ch);
+                    if let Some((_, next)) = chars.next() {
+                        result.push(next);
+                    }
+                }
+                '\'' if !in_double_quote => {
+                    in_single_quote = !in_single_quote;
+                    result.push(ch);
+                }
+                '"' if !in_single_quote => {
+                    in_double_quote = !in_double_quote;
+                    result.push(ch);
+                }
+                '$' if !in_single_quote && matches!(chars.peek(), Some((_, '('))) => {
+                    let body_start = i + 2;
+                    let body_len = substitution_end(&input[body_start..])
+                        .ok_or(ShellError::UnexpectedEof(')'))?;
+                    let body_end = body_start + body_len;
+                    while chars.next_if(|&(j, _)| j <= body_end).is_some() {}
+
+                    let output = self.capture_output(&input[body_start..body_end])?;
+                    let output = output.trim_end_matches('\n');
+                    // Without splitting, the output goes in as one quoted word
+                    let add_quotes = !split && !in_double_quote;
+                    let quoted = in_double_quote || add_quotes;
+                    if add_quotes {
+                        result.push('"');
+                    }
+                    for out_ch in output.chars() {
+                        match out_ch {
+                            // Unquoted, whitespace separates words
+                            '\n' | ' ' | '\t' if !quoted => result.push(' '),
+                            '\\' | '"' | '$' | '`' if quoted => {
+                                result.push('\\');
+                                result.push(out_ch);
+                            }
+                            _ if quoted || out_ch.is_alphanumeric() => result.push(out_ch),
+                            _ => {
+                                result.push('\\');
+                                result.push(out_ch);
+                            }
+                        }
+                    }
+                    if add_quotes {
+                        result.push('"');
+                    }
+                }
+                _ => result.push(ch),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Run `command` with stdout sent into a pipe and return what it wrote,
+    /// recording its status in `last_status`.
+    fn capture_output(&mut self, command: &str) -> Result<String, ShellError> {
+        use nix::unistd::{close, dup, dup2};
+        use std::io::Read;
+        use std::os::fd::AsRawFd;
+
+        let fail = |err: &dyn std::fmt::Display| {
+            ShellError::builtin("command substitution", err.to_string())
+        };
+
+        self.out.flush().ok();
+        let (mut reader, writer) = create_pipe().map_err(|err| fail(&err))?;
+        let saved_stdout = dup(1).map_err(|err| fail(&err))?;
+        dup2(writer.as_raw_fd(), 1).map_err(|err| fail(&err))?;
+        drop(writer);
+
+        // Drain the pipe while the command runs so a large output can't block it
+        let collector = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).ok();
+            output
+        });
+
+        self.substitution_depth += 1;
+        self.execute(command);
+        self.substitution_depth -= 1;
+        self.substitution_exit = false;
+
+        self.out.flush().ok();
+        dup2(saved_stdout, 1).ok();
+        close(saved_stdout).ok();
+
+        let output = collector.join().unwrap_or_default();
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Replace the unquoted alias names in command position, at the start
+    /// of each pipeline stage, with their values.
+    fn expand_alias(&self, input: &str) -> String {
+        let pipes = lexer::tokenize(input).unwrap_or_default();
+        let mut expanded = String::new();
+        let mut start = 0;
+        for pipe in pipes
+            .iter()
+            .filter(|token| token.kind == lexer::TokenKind::Operator)
+            .filter(|token| &input[token.span.clone()] == "|")
+        {
+            expanded.push_str(&self.expand_command_word(&input[start..pipe.span.start], &[]));
+            expanded.push('|');
+            start = pipe.span.end;
+        }
+        expanded.push_str(&self.expand_command_word(&input[start..], &[]));
+        expanded
+    }
+
+    /// Expand an alias at the start of `command`. Its value is in command
+    /// position too, except for the aliases in `expanding`, so
+    /// `alias ls='ls -F'` doesn't recurse; a value ending in a blank makes
+    /// the next word a command word as well, as in `alias sudo='sudo '`.
+    fn expand_command_word(&self, command: &str, expanding: &[&str]) -> String {
+        let word_start = command.len() - command.trim_start().len();
+        let word_end = command[word_start..]
+            .find([' ', '\t'])
+            .map_or(command.len(), |end| word_start + end);
+        let word = &command[word_start..word_end];
+        let Some(value) = self
+            .aliases
+            .get(word)
+            .filter(|_| !expanding.contains(&word))
+        else {
+            return command.to_string();
+        };
+
+        let mut inner = expanding.to_vec();
+        inner.push(word);
+        let rest = &command[word_end..];
+        let rest = if value.ends_with([' ', '\t']) {
+            self.expand_command_word(rest, expanding)
+        } else {
+            rest.to_string()
+        };
+        format!(
+            "{}{}{}",
+            &command[..word_start],
+            self.expand_command_word(value, &inner),
+            rest
+        )
+    }
+
+    /// `alias NAME=value` defines an alias, `alias NAME` shows one and bare
+    /// `alias` lists them all.
+    fn run_alias(&mut self, args: &[String]) -> i32 {
+        if args.is_empty() {
+            let (listing, status) = self.alias_listing(args);
+            self.out.write_all(&listing).ok();
+            return status;
+        }
+
+        let mut status = 0;
+        for arg in args {
+            match arg.split_once('=') {
+                Some((name, value)) => {
+                    self.aliases.insert(name.to_string(), value.to_string());
+                }
+                None => {
+                    let (listing, found) = self.alias_listing(std::slice::from_ref(arg));
+                    self.out.write_all(&listing).ok();
+                    status = status.max(found);
+                }
+            }
+        }
+        status
+    }
+
+    /// The `alias NAME='value'` lines for `names`, or for every alias
+    /// (sorted) when `names` is empty. The status is 1 if a name isn't an
+    /// alias.
+    fn alias_listing(&self, names: &[String]) -> (Vec<u8>, i32) {
+        let mut all: Vec<&String> = self.aliases.keys().collect();
+        all.sort();
+        let names = if names.is_empty() {
+            all
+        } else {
+            names.iter().collect()
+        };
+
+        let mut output = Vec::new();
+        let mut status = 0;
+        for name in names {
+            match self.aliases.get(name) {
+                Some(value) => {
+                    writeln!(output, "alias {}={}", name, single_quote(value)).ok();
+                }
+                None => {
+                    status = report(ShellError::builtin("alias", format!("{}: not found", name)));
+                }
+            }
+        }
+        (output, status)
+    }
+
+    /// What a pipeline stage running `set`, `alias`, `jobs` or `history`
+    /// prints, for the listing forms that only read the shell's state.
+    /// Other stages, and forms that would change the state, give `None`.
+    fn stage_listing(&mut self, stage: &[String]) -> Option<(Vec<u8>, i32)> {
+        let (name, args) = stage.split_first()?;
+        match (name.as_str(), args) {
+            ("set", []) => Some((set_listing(&self.variables), 0)),
+            ("alias", names) if names.iter().all(|name| !name.contains('=')) => {
+                Some(self.alias_listing(names))
+            }
+            ("jobs", _) => Some((self.job_report(true), 0)),
+            ("history", []) => Some((self.history_listing(None), 0)),
+            ("history", [count]) => count
+                .parse()
+                .ok()
+                .map(|count| (self.history_listing(Some(count)), 0)),
+            _ => None,
+        }
+    }
+
+    /// `unalias NAME...` removes aliases; `unalias -a` removes all of them.
+    fn run_unalias(&mut self, names: &[String]) -> i32 {
+        if names.first().is_some_and(|flag| flag == "-a") {
+            self.aliases.clear();
+            return 0;
+        }
+
+        let mut status = 0;
+        for name in names {
+            if self.aliases.remove(name).is_none() {
+                status = report(ShellError::builtin(
+                    "unalias",
+                    format!("{}: not found", name),
+                ));
+            }
+        }
+        status
+    }
+
+    fn run_history(&mut self, args: &[String]) -> Result<i32, ShellError> {
+        let flag_path = |flag: &str| match args {
+            [given, path] if given == flag => Some(path.as_str()),
+            _ => None,
+        };
+
+        // Check for history -r <path>
+        if let Some(path) = flag_path("-r") {
+            // Read history from file
+            let file = File::open(path).map_err(|_| {
+                ShellError::builtin("history", format!("{}: No such file or directory", path))
+            })?;
+            self.read_history(file);
+            return Ok(0);
+        }
+
+        // Check for history -w <path>
+        if let Some(path) = flag_path("-w") {
+            // Write history to file
+            let mut file = File::create(path).map_err(|_| {
+                ShellError::builtin("history", format!("{}: Cannot create file", path))
+            })?;
+            for cmd in &self.command_history {
+                writeln!(file, "{}", cmd).ok();
+            }
+            return Ok(0);
+        }
+
+        // Check for history -a <path>
+        if let Some(path) = flag_path("-a") {
+            // Append new commands to file
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|_| {
+                    ShellError::builtin("history", format!("{}: Cannot create file", path))
+                })?;
+            // Append only commands that haven't been appended yet
+            for cmd in &self.command_history[self.last_appended_index..] {
+                writeln!(file, "{}", cmd).ok();
+            }
+            // Update the last appended index
+            self.last_appended_index = self.command_history.len();
+            return Ok(0);
+        }
+
+        let limit = args.first().and_then(|n_str| n_str.parse::<usize>().ok());
+        let listing = self.history_listing(limit);
+        self.out.write_all(&listing).ok();
+        Ok(0)
+    }
+
+    /// The numbered history lines, all of them or the last `limit`.
+    fn history_listing(&self, limit: Option<usize>) -> Vec<u8> {
+        let entries_to_show = if let Some(n) = limit {
+            // Show last n entries
+            let start_index = self.command_history.len().saturating_sub(n);
+            &self.command_history[start_index..]
+        } else {
+            // Show all entries
+            &self.command_history[..]
+        };
+
+        let mut output = Vec::new();
+        let start_number = self.command_history.len() - entries_to_show.len() + 1;
+        for (index, cmd) in entries_to_show.iter().enumerate() {
+            writeln!(output, "{:5}  {}", start_number + index, cmd).ok();
+        }
+        output
+    }
+
+    /// Run an external command, with `env` added to its environment, and
+    /// wait for it, or with `background` (the command line as typed) start
+    /// it as a job.
+    fn run_external(
+        &mut self,
+        parts: &[Arg],
+        env: &[(String, String)],
+        background: Option<&str>,
+    ) -> Result<i32, ShellError> {
+        // Check for output redirection
+        let (parts, close_stdout, close_stderr) = take_fd_closes(parts);
+        let Redirections {
+            cmd_parts,
+            stdin_file,
+            stdin_data,
+            stdout_file,
+            stdout_append,
+            stderr_file,
+            stderr_append,
+            stdout_to_stderr,
+            stderr_to_stdout,
+        } = parse_redirection(&parts);
+
+        if cmd_parts.is_empty() {
+            return Ok(0);
+        }
+
+        let cmd = cmd_parts[0].as_str();
+
+        // Check if it's a builtin that doesn't need arguments
+        if cmd == "type" {
+            return Err(ShellError::CommandNotFound(cmd.to_string()));
+        }
+
+        // Search for executable in PATH
+        let path = find_in_path(cmd).ok_or_else(|| ShellError::CommandNotFound(cmd.to_string()))?;
+        let args = &cmd_parts[1..];
+
+        let mut command = Command::new(path);
+        command.arg0(cmd).args(args).envs(env.iter().cloned());
+
+        // Setup stdin redirection if specified
+        if let Some(ref file_path) = stdin_file {
+            command.stdin(Stdio::from(open_input(file_path)?));
+        }
+        let has_stdin_data = stdin_data.is_some();
+        if let Some(data) = stdin_data {
+            let reader = data_reader(data).map_err(|err| spawn_error(cmd, err))?;
+            command.stdin(Stdio::from(reader));
+        }
+
+        // Setup stdout redirection if specified; the null device needs no file
+        let mut stdout_target = None;
+        if let Some(ref file_path) = stdout_file {
+            if file_path == NULL_DEVICE {
+                command.stdout(Stdio::null());
+            } else {
+                let file = open_redirect(file_path, stdout_append)?;
+                let copy = file.try_clone().map_err(|err| spawn_error(cmd, err))?;
+                command.stdout(Stdio::from(file));
+                stdout_target = Some(copy);
+            }
+        }
+
+        // Setup stderr redirection if specified; `> log 2>&1` shares one open file
+        if let Some(ref file_path) = stderr_file {
+            if file_path == NULL_DEVICE {
+                command.stderr(Stdio::null());
+            } else {
+                let file = match stdout_target {
+                    Some(file) if stdout_file.as_ref() == Some(file_path) => file,
+                    _ => open_redirect(file_path, stderr_append)?,
+                };
+                command.stderr(Stdio::from(file));
+            }
+        }
+
+        // Duplicated onto the shell's own stream, the child writes there
+        // directly; anything still buffered must go out first
+        if stdout_to_stderr || stderr_to_stdout {
+            self.out.flush().ok();
+        }
+        if stdout_to_stderr {
+            let stderr_copy = io::stderr()
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|err| spawn_error(cmd, err))?;
+            command.stdout(Stdio::from(stderr_copy));
+            if stderr_file.is_none() {
+                command.stderr(Stdio::inherit());
+            }
+        }
+        if stderr_to_stdout {
+            let stdout_copy = io::stdout()
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|err| spawn_error(cmd, err))?;
+            command.stderr(Stdio::from(stdout_copy));
+            if stdout_file.is_none() {
+                command.stdout(Stdio::inherit());
+            }
+        }
+
+        // `>&-` / `2>&-` close the descriptor in the child just before exec
+        if close_stdout || close_stderr {
+            // SAFETY: close(2) is async-signal-safe and touches no shared state
+            unsafe {
+                command.pre_exec(move || {
+                    if close_stdout {
+                        nix::unistd::close(1).ok();
+                    }
+                    if close_stderr {
+                        nix::unistd::close(2).ok();
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        // Like `Command::output`, children don't read the shell's stdin
+        if stdin_file.is_none() && !has_stdin_data {
+            command.stdin(Stdio::null());
+        }
+
+        if let Some(command_line) = background {
+            // Background jobs write straight to the terminal. In a process
+            // group of their own, a Ctrl-C meant for the foreground command
+            // doesn't reach them
+            self.out.flush().ok();
+            command.process_group(0);
+            let child = command.spawn().map_err(|err| spawn_error(cmd, err))?;
+            let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+            eprintln!("[{}] {}", id, child.id());
+            self.jobs.push(Job {
+                id,
+                command: command_line.to_string(),
+                child,
+            });
+            return Ok(0);
+        }
+
+        // Capture whatever isn't redirected, as `Command::output` would
+        let duplicated = stdout_to_stderr || stderr_to_stdout;
+        if stdout_file.is_none() && !duplicated {
+            command.stdout(Stdio::piped());
+        }
+        if stderr_file.is_none() && !duplicated {
+            command.stderr(Stdio::piped());
+        }
+        let child = command.spawn().map_err(|err| spawn_error(cmd, err))?;
+        FOREGROUND_CHILD.store(child.id() as i32, Ordering::Relaxed);
+        let output = child.wait_with_output();
+        FOREGROUND_CHILD.store(0, Ordering::Relaxed);
+        let output = output.map_err(|err| spawn_error(cmd, err))?;
+
+        // Streams sent elsewhere come back empty
+        self.out.write_all(&output.stdout).ok();
+        io::stderr().write_all(&output.stderr).ok();
+        Ok(exit_status_code(output.status))
+    }
+}
+
+fn main() {
+    let mut shell = Shell::new();
+    // An inherited PWD that no longer names this directory is replaced
+    if let (Some(_), Ok(cwd)) = (env::var_os("PWD"), logical_cwd()) {
+        env::set_var("PWD", cwd);
+    }
+
+    // Load history from the previous session, creating the file on first run
+    if let Some(histfile) = history_file() {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&histfile);
+        if let Ok(file) = file {
+            shell.read_history(file);
+        }
+    }
+
+    // A login shell (`--login`, or `-popper` as argv[0]) reads the profiles;
+    // other interactive shells read the rc file. The system profile is
+    // popper's own: /etc/profile is written in sh syntax popper can't parse.
+    let args: Vec<String> = env::args().collect();
+    let arg0 = args.first().map(String::as_str).unwrap_or_default();
+    let has_flag = |flags: &[&str]| args.iter().skip(1).any(|arg| flags.contains(&arg.as_str()));
+    let login = arg0.starts_with('-') || has_flag(&["--login", "-l"]);
+    // `-r` or running as `rpopper` gives a restricted shell, as bash does
+    let restricted = arg0.trim_start_matches('-').rsplit('/').next() == Some("rpopper")
+        || has_flag(&["--restricted", "-r"]);
+    let home = env::var("HOME").ok();
+    let startup_files = if login {
+        vec![
+            Some("/etc/popper_profile".to_string()),
+            home.map(|home| format!("{}/.popper_profile", home)),
+        ]
+    } else if io::stdin().is_terminal() {
+        vec![home.map(|home| format!("{}/.popperrc", home))]
+    } else {
+        Vec::new()
+    };
+    for path in startup_files.into_iter().flatten() {
+        if Path::new(&path).is_file() {
+            shell.source_file(&path);
+        }
+    }
+    // The startup files may still set things up before the restrictions apply
+    shell.restricted = restricted;
+
+    loop {
+        shell.reap_jobs();
+        shell.share_jobs();
+        // Flush whatever the previous command produced before prompting
+        shell.out.flush().ok();
+
+        let prompt = shell.prompt_for("PS1", "$ ");
+        let readline = shell.editor.readline(&prompt);
+
+        let raw_input = match readline {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                // Save history before exiting
+                let status = shell.last_status;
+                shell.exit(status);
+            }
+            Err(_) => {
+                continue;
+            }
+        };
+
+        // Keep reading while a quote is open or the line ends in a backslash
+        let raw_input = match shell.read_continuation(raw_input) {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("{}", err);
+                shell.last_status = err.exit_code();
+                continue;
+            }
+        };
+
+        // Here-document bodies follow the line that announces them
+        let prompt = shell.prompt_for("PS2", "> ");
+        shell.here_docs = collect_here_docs(&raw_input, || shell.editor.readline(&prompt).ok());
+
+        // Expand `!!`, `!$` and `!^` against the previous command, echoing the result
+        let raw_input = match expand_history(&raw_input, shell.command_history.last()) {
+            Ok(Some(expanded)) => {
+                writeln!(shell.out, "{}", expanded.trim()).ok();
+                expanded
+            }
+            Ok(None) => raw_input,
+            Err(err) => {
+                eprintln!("{}", err);
+                shell.last_status = err.exit_code();
+                continue;
+            }
+        };
+        let input = raw_input.trim();
+        shell.refresh_terminal_size();
+
+        // Add non-empty commands to history
+        if !input.is_empty() && should_record_history(&raw_input) {
+            shell.command_history.push(input.to_string());
+            // Also add to rustyline's history for up/down arrow navigation
+            shell.editor.add_history_entry(input).ok();
+        }
+
+        // With REPORTTIME set, commands slower than that many seconds report their times
+        let report_after = shell.report_time();
+        let started = Instant::now();
+        let (user_before, system_before) = children_cpu_time();
+
+        shell.execute(input);
+
+        if let Some(threshold) = report_after {
+            let real = started.elapsed();
+            if real > threshold {
+                let (user, system) = children_cpu_time();
+                shell.out.flush().ok();
+                eprintln!(
+                    "{}",
+                    format_timing(input, real, user - user_before, system - system_before)
+                );
+            }
+        }
+    }
+}
+
+/// User and system CPU time used so far by finished child processes.
+fn children_cpu_time() -> (Duration, Duration) {
+    use nix::sys::resource::{getrusage, UsageWho};
+    use nix::sys::time::TimeValLike;
+
+    match getrusage(UsageWho::RUSAGE_CHILDREN) {
+        Ok(usage) => (
+            Duration::from_micros(usage.user_time().num_microseconds() as u64),
+            Duration::from_micros(usage.system_time().num_microseconds() as u64),
+        ),
+        Err(_) => (Duration::ZERO, Duration::ZERO),
+    }
+}
+
+/// A zsh-style timing line: `sleep 1  0.00s user 0.00s system 0% cpu 1.003 total`.
+fn format_timing(command: &str, real: Duration, user: Duration, system: Duration) -> String {
+    let cpu = (user + system).as_secs_f64() / real.as_secs_f64().max(f64::EPSILON) * 100.0;
+    format!(
+        "{}  {:.2}s user {:.2}s system {:.0}% cpu {:.3} total",
+        command,
+        user.as_secs_f64(),
+        system.as_secs_f64(),
+        cpu,
+        real.as_secs_f64()
+    )
+}
+
+/// Where history persists between sessions: `$HISTFILE`, defaulting to
+/// `~/.popper_history`. An empty `HISTFILE` turns persistence off.
+fn history_file() -> Option<String> {
+    match env::var("HISTFILE") {
+        Ok(histfile) if histfile.is_empty() => None,
+        Ok(histfile) => Some(histfile),
+        Err(_) => {
+            let home = env::var("HOME").ok()?;
+            Some(format!("{}/.popper_history", home.trim_end_matches('/')))
+        }
+    }
+}
+
+/// Expand the escapes in a `PS1` or `PS2` prompt: `\w` is the working directory
+/// (`~` for HOME), `\W` its last component, `\u` the user name, `\h` the
+/// host name up to the first `.` (`\H` all of it), `\$` is `#` for root and
+/// `$` otherwise, `\n` a newline and `\\` a backslash. Anything else is
+/// kept as typed.
+fn expand_prompt(ps1: &str, cwd: &Path, home: Option<&str>) -> String {
+    let mut prompt = String::new();
+    let mut chars = ps1.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            prompt.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => {
+                let cwd = cwd.to_string_lossy();
+                match home.and_then(|home| cwd.strip_prefix(home.trim_end_matches('/'))) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                        prompt.push('~');
+                        prompt.push_str(rest);
+                    }
+                    _ => prompt.push_str(&cwd),
+                }
+            }
+            Some('W') => match cwd.file_name() {
+                Some(name) if home.map(Path::new) != Some(cwd) => {
+                    prompt.push_str(&name.to_string_lossy())
+                }
+                Some(_) => prompt.push('~'),
+                None => prompt.push_str(&cwd.to_string_lossy()),
+            },
+            Some('u') => {
+                let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+                    .ok()
+                    .flatten();
+                match user {
+                    Some(user) => prompt.push_str(&user.name),
+                    None => prompt.push_str(&env::var("USER").unwrap_or_default()),
+                }
+            }
+            Some(escape @ ('h' | 'H')) => {
+                let host = nix::unistd::gethostname().unwrap_or_default();
+                let host = host.to_string_lossy();
+                if escape == 'h' {
+                    prompt.push_str(host.split('.').next().unwrap_or_default());
+                } else {
+                    prompt.push_str(&host);
+                }
+            }
+            Some('$') => prompt.push(if nix::unistd::geteuid().is_root() {
+                '#'
+            } else {
+                '$'
+            }),
+            Some('n') => prompt.push('\n'),
+            Some('\\') => prompt.push('\\'),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+    prompt
+}
+
+/// Why a line can't run yet and needs another line of input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Continuation {
+    /// The line ends in an unescaped backslash
+    Backslash,
+    /// A quote of this kind is still open
+    Quote(char),
+}
+
+/// Check whether `line` continues onto the next line, following the same
+/// quoting rules as `parse_arguments`.
+fn pending_continuation(line: &str) -> Option<Continuation> {
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = line.char_indices();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            // The guard consumes the escaped character, if there is one
+            '\\' if !in_single_quote && chars.next().is_none() => {
+                return Some(Continuation::Backslash);
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            // Quotes and backslashes in a comment don't continue the line
+            '#' if !in_single_quote
+                && !in_double_quote
+                && starts_comment(line[..i].chars().next_back()) =>
+            {
+                return None;
+            }
+            _ => {}
+        }
+    }
+
+    if in_single_quote {
+        Some(Continuation::Quote('\''))
+    } else if in_double_quote {
+        Some(Continuation::Quote('"'))
+    } else {
+        None
+    }
+}
+
+/// Split a trailing `&` off a command, returning what's left and whether
+/// it was there. A quoted or escaped `&` is part of the last word.
+fn take_background(command: &str) -> (&str, bool) {
+    match command.trim_end().strip_suffix('&') {
+        Some(rest) if pending_continuation(rest).is_none() => (rest.trim_end(), true),
+        _ => (command, false),
+    }
+}
+
+/// Whether an unquoted `#` following `prev` starts a comment. Like POSIX
+/// shells, only a `#` that begins a word does, so `foo#bar` is one word.
+fn starts_comment(prev: Option<char>) -> bool {
+    matches!(prev, None | Some(' ' | '\t' | ';' | '&' | '|'))
+}
+
+/// A here-document: the lines after a command with `<<WORD`, up to a line
+/// that is just WORD, which become the command's stdin.
+#[derive(Debug, Default, PartialEq)]
+struct HereDoc {
+    delimiter: String,
+    /// `<<-WORD` strips leading tabs from the body and the delimiter line
+    strip_tabs: bool,
+    /// `$NAME` in the body expands unless WORD had quotes or backslashes
+    expand: bool,
+    body: String,
+}
+
+/// Find the here-documents `line` announces, in order, with empty bodies.
+/// Quoted or commented-out `<<` don't count, and neither does `<<<`.
+fn here_doc_specs(line: &str) -> Vec<HereDoc> {
+    let mut specs = Vec::new();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                chars.next();
+            }
+            '\'' if !in_double_quote => in_single_quote = !in_single_quote,
+            '"' if !in_single_quote => in_double_quote = !in_double_quote,
+            '#' if !in_single_quote
+                && !in_double_quote
+                && starts_comment(line[..i].chars().next_back()) =>
+            {
+                break;
+            }
+            '<' if !in_single_quote && !in_double_quote && line[i..].starts_with("<<<") => {
+                chars.nth(1);
+            }
+            '<' if !in_single_quote && !in_double_quote && line[i..].starts_with("<<") => {
+                chars.next();
+                let strip_tabs = chars.next_if(|&(_, ch)| ch == '-').is_some();
+                while chars.next_if(|&(_, ch)| ch == ' ' || ch == '\t').is_some() {}
+
+                // The delimiter is the next word, with its quotes removed
+                let mut doc = HereDoc {
+                    strip_tabs,
+                    expand: true,
+                    ..HereDoc::default()
+                };
+                let mut quote = None;
+                while let Some(&(_, ch)) = chars.peek() {
+                    match (quote, ch) {
+                        (None, ' ' | '\t' | ';' | '&' | '|' | '<' | '>') => break,
+                        (None, '\'' | '"') => quote = Some(ch),
+                        (Some(open), _) if ch == open => quote = None,
+                        (None, '\\') => {
+                            chars.next();
+                            doc.delimiter.extend(chars.peek().map(|&(_, ch)| ch));
+                        }
+                        _ => doc.delimiter.push(ch),
+                    }
+                    if matches!(ch, '\'' | '"' | '\\') {
+                        doc.expand = false;
+                    }
+                    chars.next();
+                }
+                if !doc.delimiter.is_empty() || !doc.expand {
+                    specs.push(doc);
+                }
+            }
+            _ => {}
+        }
+    }
+    specs
+}
+
+/// Read the bodies of the here-documents `line` announces, taking lines
+/// from `next_line` until each one's delimiter. Running out of input ends
+/// the body where it is, with a warning.
+fn collect_here_docs(
+    line: &str,
+    mut next_line: impl FnMut() -> Option<String>,
+) -> VecDeque<HereDoc> {
+    let mut docs = VecDeque::new();
+    for mut doc in here_doc_specs(line) {
+        loop {
+            let Some(next) = next_line() else {
+                eprintln!(
+                    "popper: warning: here-document delimited by end-of-file (wanted `{}')",
+                    doc.delimiter
+                );
+                break;
+            };
+            let next = if doc.strip_tabs {
+                next.trim_start_matches('\t')
+            } else {
+                &next
+            };
+            if next == doc.delimiter {
+                break;
+            }
+            doc.body.push_str(next);
+            doc.body.push('\n');
+        }
+        docs.push_back(doc);
+    }
+    docs
+}
+
+/// How a command in a list depends on the one before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Connector {
+    /// `;`: run regardless of the previous status
+    Then,
+    /// `&&`: run only if the previous command succeeded
+    And,
+    /// `||`: run only if the previous command failed
+    Or,
+}
+
+impl Connector {
+    fn token(self) -> &'static str {
+        match self {
+            Connector::Then => ";",
+            Connector::And => "&&",
+            Connector::Or => "||",
+        }
+    }
+}
+
+/// Split a line at unquoted `;`, `&&` and `||`, pairing each command with
+/// the operator that precedes it (`None` for the first). A missing command
+/// on either side of an operator is a syntax error, except that a trailing
+/// `;` is allowed.
+fn split_list(line: &str) -> Result<Vec<(Option<Connector>, &str)>, ShellError> {
+    let mut commands = Vec::new();
+    let mut connector = None;
+    let mut start = 0;
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut end = line.len();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        let next = match ch {
+            '\\' if !in_single_quote => {
+                chars.next();
+                continue;
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+                continue;
+            }
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+                continue;
+            }
+            // Operators inside `$(...)` belong to the substituted command
+            '$' if !in_single_quote && matches!(chars.peek(), Some((_, '('))) => {
+                if let Some(len) = substitution_end(&line[i + 2..]) {
+                    while chars.next_if(|&(j, _)| j <= i + 2 + len).is_some() {}
+                }
+                continue;
+            }
+            // A comment runs to the end of the line, operators included
+            '#' if !in_single_quote
+                && !in_double_quote
+                && starts_comment(line[..i].chars().next_back()) =>
+            {
+                end = i;
+                break;
+            }
+            ';' if !in_single_quote && !in_double_quote => Connector::Then,
+            '&' | '|' if !in_single_quote && !in_double_quote => {
+                match chars.peek() {
+                    Some(&(_, next)) if next == ch => {}
+                    _ => continue,
+                }
+                chars.next();
+                if ch == '&' {
+                    Connector::And
+                } else {
+                    Connector::Or
+                }
+            }
+            _ => continue,
+        };
+
+        let command = line[start..i].trim();
+        if command.is_empty() {
+            return Err(ShellError::SyntaxError(next.token().to_string()));
+        }
+        commands.push((connector, command));
+        connector = Some(next);
+        start = i + next.token().len();
+    }
+
+    let command = line[start..end].trim();
+    match connector {
+        Some(Connector::Then) if command.is_empty() => {}
+        Some(connector) if command.is_empty() => {
+            return Err(ShellError::SyntaxError(connector.token().to_string()));
+        }
+        _ => commands.push((connector, command)),
+    }
+    Ok(commands)
+}
+
+/// Expand history references: a bare `!!` is the previous command, `!$`
+/// becomes its last word and `!^` its first argument. Single-quoted text and
+/// `\!` are left alone. Returns `None` when nothing was expanded.
+fn expand_history(line: &str, previous: Option<&String>) -> Result<Option<String>, ShellError> {
+    // A line that is exactly `!!` re-runs the previous command
+    if line.trim() == "!!" {
+        return match previous {
+            Some(previous) => Ok(Some(previous.clone())),
+            None => Err(ShellError::EventNotFound("!!".to_string())),
+        };
+    }
+
+    let mut expanded = String::new();
+    let mut changed = false;
+    let mut in_single_quote = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if !in_single_quote => {
+                expanded.push(ch);
+                if let Some(next) = chars.next() {
+                    expanded.push(next);
+                }
+            }
+            '\'' => {
+                in_single_quote = !in_single_quote;
+                expanded.push(ch);
+            }
+            '!' if !in_single_quote && matches!(chars.peek(), Some('$') | Some('^')) => {
+                let designator = chars.next().unwrap();
+                let event = format!("!{}", designator);
+                // Words go back in as typed, so `"my dir"` stays one word
+                let words = previous
+                    .and_then(|prev| lexer::words(prev).ok())
+                    .unwrap_or_default();
+                let word = match designator {
+                    '$' => words.last(),
+                    _ => words.get(1),
+                };
+                expanded.push_str(word.ok_or(ShellError::EventNotFound(event))?);
+                changed = true;
+            }
+            _ => expanded.push(ch),
+        }
+    }
+
+    Ok(changed.then_some(expanded))
+}
+
+/// Drop the first `count` words of a raw command line, keeping the rest
+/// exactly as typed so it can be run again through `run_line`.
+fn skip_words(input: &str, count: usize) -> &str {
+    let mut rest = input.trim_start();
+    for _ in 0..count {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// Parse `loop [-n SECONDS] cmd...`, returning the interval and how many
+/// words precede the command.
+fn parse_loop_args(parts: &[String]) -> Result<(Duration, usize), ShellError> {
+    let (interval, consumed) = match parts.get(1).map(String::as_str) {
+        Some("-n") => {
+            let seconds = parts.get(2).map(String::as_str).unwrap_or("");
+            let interval = seconds
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| secs.is_finite() && *secs > 0.0)
+                .ok_or_else(|| {
+                    ShellError::builtin("loop", format!("{}: invalid interval", seconds))
+                })?;
+            (Duration::from_secs_f64(interval), 3)
+        }
+        _ => (Duration::from_secs(2), 1),
+    };
+
+    if parts.len() <= consumed {
+        return Err(ShellError::builtin(
+            "loop",
+            "usage: loop [-n seconds] command",
+        ));
+    }
+    Ok((interval, consumed))
+}
+
+/// Open the source of an input redirection.
+fn open_input(path: &str) -> Result<File, ShellError> {
+    File::open(path).map_err(|source| ShellError::RedirectFailed {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Open the target of an output redirection, truncating unless appending.
+fn open_redirect(path: &str, append: bool) -> Result<File, ShellError> {
+    let file_result = if append {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+    } else {
+        File::create(path)
+    };
+
+    file_result.map_err(|source| ShellError::RedirectFailed {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Run `f` with the shell's stderr (fd 2) pointed at `target`, so an
+/// in-process builtin's diagnostics follow its `2>` redirection.
+fn with_stderr<T>(target: Option<&File>, f: impl FnOnce() -> T) -> io::Result<T> {
+    use nix::unistd::{close, dup, dup2};
+    use std::os::fd::AsRawFd;
+
+    let Some(target) = target else {
+        return Ok(f());
+    };
+    let saved_stderr = dup(2)?;
+    dup2(target.as_raw_fd(), 2)?;
+    let result = f();
+    dup2(saved_stderr, 2).ok();
+    close(saved_stderr).ok();
+    Ok(result)
+}
+
+/// Redirections to this path give the child `Stdio::null()` instead of
+/// opening the device.
+const NULL_DEVICE: &str = "/dev/null";
+
+/// Make `pgid` the terminal's foreground process group. SIGTTOU is ignored
+/// meanwhile, since the shell may not be in the foreground when it takes
+/// the terminal back.
+fn hand_terminal_to(pgid: Pid) {
+    use nix::sys::signal::{signal, SigHandler};
+
+    // SAFETY: only the disposition of SIGTTOU changes, and it's restored
+    unsafe {
+        let previous = signal(Signal::SIGTTOU, SigHandler::SigIgn);
+        nix::unistd::tcsetpgrp(0, pgid).ok();
+        if let Ok(previous) = previous {
+            signal(Signal::SIGTTOU, previous).ok();
+        }
+    }
+}
+
+/// Print `err` for a builtin that carries on after it, returning the status
+/// it stands for.
+fn report(err: ShellError) -> i32 {
+    eprintln!("{}", err);
+    err.exit_code()
+}
+
+/// Classify a failure to start `cmd`.
+fn spawn_error(cmd: &str, err: io::Error) -> ShellError {
+    match err.kind() {
+        io::ErrorKind::NotFound => ShellError::CommandNotFound(cmd.to_string()),
+        io::ErrorKind::PermissionDenied => ShellError::PermissionDenied(cmd.to_string()),
+        _ => ShellError::ExecFailed {
+            command: cmd.to_string(),
+            source: err,
+        },
+    }
+}
+
+/// A child's exit status as a shell status: its exit code, or 128 plus the
+/// signal number if it was killed by a signal.
+fn exit_status_code(status: std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    status
+        .code()
+        .or_else(|| status.signal().map(|sig| 128 + sig))
+        .unwrap_or(1)
+}
+
+/// Directories listed in `CDPATH`. An empty entry means the current directory.
+fn cdpath_entries() -> Vec<String> {
+    match env::var("CDPATH") {
+        Ok(cdpath) => cdpath
+            .split(':')
+            .map(|entry| if entry.is_empty() { "." } else { entry }.to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// CDPATH only applies to relative names that don't start with `.` or `..`.
+fn is_cdpath_candidate(path: &str) -> bool {
+    !path.starts_with('/')
+        && path != "."
+        && path != ".."
+        && !path.starts_with("./")
+        && !path.starts_with("../")
+}
+
+/// Find `path` under a non-current-directory CDPATH entry.
+fn resolve_in_cdpath(path: &str) -> Option<std::path::PathBuf> {
+    if !is_cdpath_candidate(path) {
+        return None;
+    }
+
+    for base in cdpath_entries() {
+        let candidate = Path::new(&base).join(path);
+        if candidate.is_dir() {
+            // A hit in the current directory is a plain relative cd
+            return (base != ".").then_some(candidate);
+        }
+    }
+    None
+}
+
+/// Search path used when `PATH` is not set, so the shell stays usable.
+const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+/// The directories to search for executables, falling back to `DEFAULT_PATH`.
+fn search_path() -> String {
+    env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string())
+}
+
+/// A regular file (or a symlink to one) with an execute bit set. Directories
+/// have the bit too, but can't be run.
+fn is_executable_file(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// `test EXPR` (or `[ EXPR ]`) answers through its status whether EXPR
+/// holds: 0 if it does, 1 if not and 2 if EXPR is malformed. EXPR is a file
+/// test (`-e -f -d -r -w -x`), a string test (`-z -n = !=`), an integer
+/// comparison (`-eq -ne -lt -gt -le -ge`) or a lone string, which is true
+/// when not empty. A leading `!` negates it.
+fn test_status(name: &str, args: &[String]) -> i32 {
+    let args = if name == "[" {
+        match args.split_last() {
+            Some((last, rest)) if last == "]" => rest,
+            _ => {
+                eprintln!("[: missing `]'");
+                return 2;
+            }
+        }
+    } else {
+        args
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match evaluate_test(&args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(message) => {
+            eprintln!("{}: {}", name, message);
+            2
+        }
+    }
+}
+
+/// Evaluate a `test` expression, choosing its form by the number of words
+/// as POSIX does.
+fn evaluate_test(args: &[&str]) -> Result<bool, String> {
+    const BINARY: [&str; 9] = ["=", "==", "!=", "-eq", "-ne", "-lt", "-gt", "-le", "-ge"];
+
+    match args {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        [left, op, right] if BINARY.contains(op) => binary_test(left, op, right),
+        ["!", rest @ ..] => evaluate_test(rest).map(|result| !result),
+        [op, operand] => unary_test(op, operand),
+        [_, op, _] => Err(format!("{}: binary operator expected", op)),
+        _ => Err("too many arguments".to_string()),
+    }
+}
+
+fn unary_test(op: &str, operand: &str) -> Result<bool, String> {
+    use nix::unistd::{access, AccessFlags};
+
+    let metadata = || std::fs::metadata(operand);
+    Ok(match op {
+        "-e" => metadata().is_ok(),
+        "-f" => metadata().is_ok_and(|metadata| metadata.is_file()),
+        "-d" => metadata().is_ok_and(|metadata| metadata.is_dir()),
+        "-r" => access(operand, AccessFlags::R_OK).is_ok(),
+        "-w" => access(operand, AccessFlags::W_OK).is_ok(),
+        "-x" => access(operand, AccessFlags::X_OK).is_ok(),
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        _ => return Err(format!("{}: unary operator expected", op)),
+    })
+}
+
+fn binary_test(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    let integer = |word: &str| {
+        word.trim()
+            .parse::<i64>()
+            .map_err(|_| format!("{}: integer expression expected", word))
+    };
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        _ => {
+            let (left, right) = (integer(left)?, integer(right)?);
+            match op {
+                "-eq" => left == right,
+                "-ne" => left != right,
+                "-lt" => left < right,
+                "-gt" => left > right,
+                "-le" => left <= right,
+                _ => left >= right,
+            }
+        }
+    })
+}
+
+/// `which NAME...` prints the path each NAME runs from, one per line.
+/// Names that aren't found print nothing and make the status 1.
+fn which_output(args: &[String]) -> (Vec<u8>, i32) {
+    let mut output = Vec::new();
+    let mut status = 0;
+    for name in args {
+        match find_in_path(name) {
+            Some(path) => {
+                output.extend_from_slice(path.as_bytes());
+                output.push(b'\n');
+            }
+            None => status = 1,
+        }
+    }
+    (output, status)
+}
+
+/// The directories of a colon-separated search path, in order. An empty
+/// entry means the current directory.
+fn path_dirs(path: &str) -> impl Iterator<Item = &str> {
+    path.split(':')
+        .map(|dir| if dir.is_empty() { "." } else { dir })
+}
+
+/// Where `cmd` runs from under `path`: the first directory holding an
+/// executable file of that name. Execution, `type`, `which` and
+/// completion (through `path_commands`) all agree on this.
+fn resolve_in_path(path: &str, cmd: &str) -> Option<String> {
+    path_dirs(path)
+        .map(|dir| Path::new(dir).join(cmd))
+        .find(|full_path| is_executable_file(full_path))
+        .and_then(|full_path| full_path.to_str().map(|s| s.to_string()))
+}
+
+fn find_in_path(cmd: &str) -> Option<String> {
+    resolve_in_path(&search_path(), cmd)
+}
+
+fn parse_arguments(input: &str) -> Vec<String> {
+    split_words(input, None)
+        .into_iter()
+        .map(|word| word.text)
+        .collect()
+}
+
+/// Resolves a variable name to its value, `None` when unset.
+type VariableLookup<'a> = dyn Fn(&str) -> Option<String> + 'a;
+
+/// Split a command line into words like `parse_arguments`, substituting
+/// `$NAME` and `${NAME}` with `lookup(NAME)` (empty when unset). Expansion
+/// happens unquoted and inside double quotes, never inside single quotes.
+/// Words with unquoted `*`, `?` or `[` then expand to the matching paths,
+/// staying as typed when nothing matches.
+fn expand_arguments(input: &str, lookup: &VariableLookup) -> Vec<String> {
+    let mut args = Vec::new();
+    for word in split_words(input, Some(lookup)) {
+        let matches = if word.glob {
+            expand_glob(&word.pattern)
+        } else {
+            Vec::new()
+        };
+        if matches.is_empty() {
+            args.push(word.text);
+        } else {
+            args.extend(matches);
+        }
+    }
+    args
+}
+
+/// A word of a command after expansion. Only an operator the lexer found
+/// unquoted is an `Operator`, so `'>'` or `"2>&1"` stays an argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Arg {
+    Word(String),
+    /// A redirection operator or `|`
+    Operator(String),
+}
+
+impl Arg {
+    fn text(&self) -> &str {
+        match self {
+            Arg::Word(text) | Arg::Operator(text) => text,
+        }
+    }
+
+    fn is_operator(&self, op: &str) -> bool {
+        matches!(self, Arg::Operator(text) if text == op)
+    }
+}
+
+/// The text of each of `args`, operators included.
+fn arg_texts(args: &[Arg]) -> Vec<String> {
+    args.iter().map(|arg| arg.text().to_string()).collect()
+}
+
+/// Split a command line into expanded words like `expand_arguments`,
+/// keeping the unquoted redirection operators and `|` the lexer finds
+/// apart from them, glued on (`>out`, `<<EOF`) or not. Control operators
+/// have been split off already and are left in the words.
+fn expand_command(input: &str, lookup: &VariableLookup) -> Vec<Arg> {
+    let Ok(tokens) = lexer::tokenize(input) else {
+        // An unclosed quote keeps everything as words
+        return expand_arguments(input, lookup)
+            .into_iter()
+            .map(Arg::Word)
+            .collect();
+    };
+
+    let mut args = Vec::new();
+    // The span of the word being gathered: tokens with no blank between them
+    let mut word: Option<std::ops::Range<usize>> = None;
+    let finish = |word: &mut Option<std::ops::Range<usize>>, args: &mut Vec<Arg>| {
+        if let Some(span) = word.take() {
+            args.extend(
+                expand_arguments(&input[span], lookup)
+                    .into_iter()
+                    .map(Arg::Word),
+            );
+        }
+    };
+    for token in tokens {
+        let text = &input[token.span.clone()];
+        if token.kind == lexer::TokenKind::Operator && !matches!(text, "&&" | "||" | ";" | "&") {
+            finish(&mut word, &mut args);
+            args.push(Arg::Operator(text.to_string()));
+            continue;
+        }
+        match &mut word {
+            Some(span) if span.end == token.span.start => span.end = token.span.end,
+            _ => {
+                finish(&mut word, &mut args);
+                word = Some(token.span);
+            }
+        }
+    }
+    finish(&mut word, &mut args);
+    args
+}
+
+/// A word as the lexer saw it: its text with quotes removed, plus the same
+/// text as a glob pattern in which quoted characters are escaped.
+#[derive(Debug, Default)]
+struct Word {
+    text: String,
+    pattern: String,
+    /// Whether an unquoted `*`, `?` or `[` appears
+    glob: bool,
+}
+
+impl Word {
+    /// Add a character that came from inside quotes or after a backslash.
+    fn push_quoted(&mut self, ch: char) {
+        if matches!(ch, '*' | '?' | '[' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(ch);
+        self.text.push(ch);
+    }
+
+    /// Add a character that the glob pass may treat as special.
+    fn push_unquoted(&mut self, ch: char) {
+        if matches!(ch, '*' | '?' | '[') {
+            self.glob = true;
+        }
+        self.pattern.push(ch);
+        self.text.push(ch);
+    }
+}
+
+fn split_words(input: &str, lookup: Option<&VariableLookup>) -> Vec<Word> {
+    let mut args = Vec::new();
+    let mut current_arg = Word::default();
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = input.chars().peekable();
+    let mut prev = None;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '#' if !in_single_quote && !in_double_quote && starts_comment(prev) => break,
+            '\\' if !in_single_quote => {
                 // Backslash escapes certain special characters
                 if let Some(&next_ch) = chars.peek() {
                     // In double quotes, only escape: \ " $ `
@@ -482,303 +3247,2128 @@ fn parse_arguments(input: &str) -> Vec<String> {
                     if in_double_quote {
                         if next_ch == '\\' || next_ch == '"' || next_ch == '$' || next_ch == '`' {
                             chars.next(); // consume the next character
-                            current_arg.push(next_ch);
+                            current_arg.push_quoted(next_ch);
                         } else {
                             // Not a special character, keep the backslash
-                            current_arg.push(ch);
+                            current_arg.push_quoted(ch);
+                        }
+                    } else {
+                        // Outside quotes, backslash escapes any character
+                        chars.next(); // consume the next character
+                        current_arg.push_quoted(next_ch);
+                    }
+                }
+            }
+            '\'' if !in_double_quote => {
+                in_single_quote = !in_single_quote;
+            }
+            '$' if !in_single_quote && lookup.is_some() => match read_parameter(&mut chars) {
+                Some(name) => {
+                    if let Some(value) = lookup.and_then(|lookup| lookup(&name)) {
+                        for value_ch in value.chars() {
+                            if in_double_quote {
+                                current_arg.push_quoted(value_ch);
+                            } else {
+                                current_arg.push_unquoted(value_ch);
+                            }
+                        }
+                    }
+                }
+                // Not a parameter reference (e.g. `$` before a space): keep it
+                None => current_arg.push_quoted(ch),
+            },
+            '"' if !in_single_quote => {
+                in_double_quote = !in_double_quote;
+            }
+            // A leading unquoted `~` or `~user` names a home directory
+            '~' if !in_single_quote
+                && !in_double_quote
+                && current_arg.text.is_empty()
+                && lookup.is_some() =>
+            {
+                match read_tilde_prefix(&mut chars, lookup) {
+                    Some(home) => home.chars().for_each(|ch| current_arg.push_quoted(ch)),
+                    None => current_arg.push_unquoted(ch),
+                }
+            }
+            ' ' | '\t' if !in_single_quote && !in_double_quote => {
+                if !current_arg.text.is_empty() {
+                    args.push(std::mem::take(&mut current_arg));
+                }
+            }
+            _ if in_single_quote || in_double_quote => {
+                current_arg.push_quoted(ch);
+            }
+            _ => {
+                current_arg.push_unquoted(ch);
+            }
+        }
+        prev = Some(ch);
+    }
+
+    if !current_arg.text.is_empty() {
+        args.push(current_arg);
+    }
+
+    args
+}
+
+/// Expand a glob pattern into the sorted paths it matches, one `/`-separated
+/// component at a time. Hidden entries only match a component that starts
+/// with `.` itself.
+fn expand_glob(pattern: &str) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+
+    let components: Vec<&str> = rest.split('/').collect();
+    for (index, component) in components.iter().enumerate() {
+        let mut next = Vec::new();
+        for base in &paths {
+            if !has_glob_chars(component) {
+                next.push(format!("{}{}", base, unescape_glob(component)));
+                continue;
+            }
+
+            let dir = if base.is_empty() { "." } else { base.as_str() };
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if glob_match(component, &name) {
+                    next.push(format!("{}{}", base, name));
+                }
+            }
+        }
+        if index + 1 < components.len() {
+            for path in &mut next {
+                path.push('/');
+            }
+        }
+        paths = next;
+    }
+
+    // Literal components were taken on trust; keep only paths that exist
+    let mut matches: Vec<String> = paths
+        .into_iter()
+        .filter(|path| std::fs::symlink_metadata(path).is_ok())
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Whether a glob pattern has an unescaped `*`, `?` or `[`.
+fn has_glob_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Drop the backslashes a glob pattern uses to escape characters.
+fn unescape_glob(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => text.extend(chars.next()),
+            _ => text.push(ch),
+        }
+    }
+    text
+}
+
+/// Resolve a tilde prefix, the `~` already consumed: `~` alone is `$HOME`
+/// and `~user` is that user's home directory. The prefix must end the word
+/// or be followed by `/`. Leaves `chars` untouched and returns `None` when
+/// there is nothing to expand.
+fn read_tilde_prefix(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    lookup: Option<&VariableLookup>,
+) -> Option<String> {
+    let mut lookahead = chars.clone();
+    let mut user = String::new();
+    while let Some(&ch) = lookahead.peek() {
+        if ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '.') {
+            user.push(ch);
+            lookahead.next();
+        } else {
+            break;
+        }
+    }
+    if !matches!(lookahead.peek(), None | Some('/' | ' ' | '\t')) {
+        return None;
+    }
+
+    let home = if user.is_empty() {
+        lookup.and_then(|lookup| lookup("HOME"))?
+    } else {
+        let user = nix::unistd::User::from_name(&user).ok()??;
+        user.dir.to_str()?.to_string()
+    };
+    *chars = lookahead;
+    Some(home)
+}
+
+/// Read the name of a `$NAME`, `${NAME}` or special `$?` reference, the `$`
+/// already consumed. `${!NAME}` and `${!PREFIX*}` come back with their `!`
+/// (and `*` or `@`) for the lookup to handle. Leaves `chars` untouched and
+/// returns `None` when what follows isn't a valid reference.
+fn read_parameter(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let mut lookahead = chars.clone();
+    let mut name = String::new();
+
+    if lookahead.peek() == Some(&'{') {
+        lookahead.next();
+        let mut closed = false;
+        for ch in lookahead.by_ref() {
+            if ch == '}' {
+                closed = true;
+                break;
+            }
+            name.push(ch);
+        }
+        if !closed {
+            return None;
+        }
+        let indirect = name
+            .strip_prefix('!')
+            .map(|rest| rest.strip_suffix(['*', '@']).unwrap_or(rest));
+        if !is_valid_name(indirect.unwrap_or(&name)) && name != "?" {
+            return None;
+        }
+        // Skip the braces and the name
+        chars.nth(name.chars().count() + 1);
+        return Some(name);
+    }
+
+    if lookahead.peek() == Some(&'?') {
+        chars.next();
+        return Some("?".to_string());
+    }
+
+    while let Some(&ch) = lookahead.peek() {
+        if ch.is_ascii_alphanumeric() || ch == '_' {
+            name.push(ch);
+            lookahead.next();
+        } else {
+            break;
+        }
+    }
+    if !is_valid_name(&name) {
+        return None;
+    }
+    *chars = lookahead;
+    Some(name)
+}
+
+/// Refuse what a restricted shell doesn't allow in any stage of `parts`:
+/// `cd`, setting `PATH` or `SHELL`, naming a command by path, and
+/// redirecting output.
+fn check_restricted(parts: &[Arg]) -> Result<(), ShellError> {
+    let denied = |what: &str| Err(ShellError::Restricted(what.to_string()));
+
+    for stage in parts.split(|part| part.is_operator("|")) {
+        let (stage, _, _) = take_fd_closes(stage);
+        let redirections = parse_redirection(&stage);
+        if redirections.stdout_file.is_some()
+            || redirections.stderr_file.is_some()
+            || redirections.stdout_to_stderr
+            || redirections.stderr_to_stdout
+        {
+            return denied("redirect output");
+        }
+
+        // `builtin` and `env` come before the command they run; `env`'s
+        // assignments set variables just like `export` does
+        let (words, mut variables) = match redirections.cmd_parts.split_first() {
+            Some((name, rest)) if name == "builtin" => (rest, Vec::new()),
+            Some((name, rest)) if name == "env" => {
+                let (assignments, command) = split_env_assignments(rest);
+                (
+                    command,
+                    assignments.into_iter().map(|(name, _)| name).collect(),
+                )
+            }
+            _ => (&redirections.cmd_parts[..], Vec::new()),
+        };
+        if let Some((name, args)) = words.split_first() {
+            if name.contains('/') {
+                return denied("specify `/' in command names");
+            }
+            match name.as_str() {
+                "cd" => return denied("change directory"),
+                "export" | "unset" => variables.extend(args.iter().map(|arg| {
+                    arg.split_once('=')
+                        .map_or(arg.clone(), |(name, _)| name.to_string())
+                })),
+                _ => {}
+            }
+        }
+        if let Some(variable) = variables
+            .iter()
+            .find(|name| *name == "PATH" || *name == "SHELL")
+        {
+            return denied(&format!("set {}", variable));
+        }
+    }
+    Ok(())
+}
+
+/// Strip `>&-`, `1>&-` and `2>&-` (close stdout / stderr) from a command,
+/// returning the remaining words and which descriptors to close.
+fn take_fd_closes(parts: &[Arg]) -> (Vec<Arg>, bool, bool) {
+    let mut remaining = Vec::new();
+    let mut close_stdout = false;
+    let mut close_stderr = false;
+
+    for part in parts {
+        match part {
+            Arg::Operator(op) if op == ">&-" || op == "1>&-" => close_stdout = true,
+            Arg::Operator(op) if op == "2>&-" => close_stderr = true,
+            _ => remaining.push(part.clone()),
+        }
+    }
+
+    (remaining, close_stdout, close_stderr)
+}
+
+/// A command split from its redirections by `parse_redirection`.
+#[derive(Debug, Default, PartialEq)]
+struct Redirections {
+    /// The command and its arguments
+    cmd_parts: Vec<String>,
+    /// `< file`
+    stdin_file: Option<String>,
+    /// A here-document's body, which `Shell::attach_here_docs` puts after
+    /// its `<<`, or a `<<< word` here-string with a newline added
+    stdin_data: Option<String>,
+    /// `> file`, or `>> file` when `stdout_append` is set
+    stdout_file: Option<String>,
+    stdout_append: bool,
+    /// `2> file`, or `2>> file` when `stderr_append` is set
+    stderr_file: Option<String>,
+    stderr_append: bool,
+    /// `1>&2` with stderr not redirected: stdout goes to the shell's stderr
+    stdout_to_stderr: bool,
+    /// `2>&1` with stdout not redirected: stderr goes to the shell's stdout
+    stderr_to_stdout: bool,
+}
+
+/// Split the redirections off a command. Besides the stdin, stdout and
+/// stderr files, `2>&1` and `1>&2` are applied in order: each one copies
+/// wherever the other stream points at that moment, so `> log 2>&1` sends
+/// both to `log` while `2>&1 > log` leaves stderr on the shell's stdout.
+fn parse_redirection(parts: &[Arg]) -> Redirections {
+    let mut redirections = Redirections::default();
+    let mut parts = parts.iter().peekable();
+
+    while let Some(part) = parts.next() {
+        let op = match part {
+            Arg::Operator(op) => op.as_str(),
+            Arg::Word(word) => {
+                redirections.cmd_parts.push(word.clone());
+                continue;
+            }
+        };
+
+        // Check for 2>&1 and 1>&2 (duplicate one stream onto the other)
+        if op == "2>&1" {
+            if !redirections.stdout_to_stderr {
+                redirections.stderr_file = redirections.stdout_file.clone();
+                redirections.stderr_append = redirections.stdout_append;
+                redirections.stderr_to_stdout = redirections.stdout_file.is_none();
+            }
+            continue;
+        } else if op == "1>&2" || op == ">&2" {
+            if !redirections.stderr_to_stdout {
+                redirections.stdout_file = redirections.stderr_file.clone();
+                redirections.stdout_append = redirections.stderr_append;
+                redirections.stdout_to_stderr = redirections.stderr_file.is_none();
+            }
+            continue;
+        }
+
+        // Every other redirection takes the next word as its target
+        let target = match parts.peek() {
+            Some(Arg::Word(target)) => target.clone(),
+            _ => {
+                redirections.cmd_parts.push(op.to_string());
+                continue;
+            }
+        };
+        match op {
+            // stdin from a file, a here-document or a word
+            "<" => {
+                redirections.stdin_file = Some(target);
+                redirections.stdin_data = None;
+            }
+            "<<" => {
+                redirections.stdin_data = Some(target);
+                redirections.stdin_file = None;
+            }
+            "<<<" => {
+                redirections.stdin_data = Some(format!("{}\n", target));
+                redirections.stdin_file = None;
+            }
+            // A file redirection replaces any earlier duplication of that stream
+            ">" | "1>" | ">>" | "1>>" => {
+                redirections.stdout_file = Some(target);
+                redirections.stdout_append = op.ends_with(">>");
+                redirections.stdout_to_stderr = false;
+            }
+            "2>" | "2>>" => {
+                redirections.stderr_file = Some(target);
+                redirections.stderr_append = op == "2>>";
+                redirections.stderr_to_stdout = false;
+            }
+            _ => {
+                redirections.cmd_parts.push(op.to_string());
+                continue;
+            }
+        }
+        parts.next();
+    }
+
+    redirections
+}
+
+/// Split a command made only of `NAME=value` words into the names and the
+/// values as typed, or `None` if it is anything else.
+fn assignment_words(input: &str) -> Option<Vec<(&str, &str)>> {
+    let words = lexer::words(input).ok()?;
+    if words.is_empty() {
+        return None;
+    }
+    words
+        .into_iter()
+        .map(|word| {
+            let (name, value) = word.split_once('=')?;
+            is_valid_name(name).then_some((name, value))
+        })
+        .collect()
+}
+
+/// Shell variable names: a letter or underscore, then letters, digits or underscores.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The directory operand of `cd [-L|-P] [--] [DIR]`, and whether the last
+/// of `-L` and `-P` asked for the physical path. `--` ends the options so
+/// a directory named like one (`cd -- -P`) can still be entered.
+fn cd_operand(args: &[String]) -> Result<(Option<&str>, bool), ShellError> {
+    let mut physical = false;
+    let mut args = args.iter();
+    for arg in args.by_ref() {
+        match arg.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            "--" => break,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(ShellError::builtin(
+                    "cd",
+                    format!("{}: invalid option", flag),
+                ));
+            }
+            path => return Ok((Some(path), physical)),
+        }
+    }
+    Ok((args.next().map(String::as_str), physical))
+}
+
+/// Follow `path` from the logical directory `base` the way `cd -L` does:
+/// `.` and `..` are folded away by name, so `..` leaves a symlink the way
+/// it was entered rather than going to its target's parent.
+fn logical_join(base: &Path, path: &Path) -> PathBuf {
+    let mut joined = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                joined.pop();
+            }
+            other => joined.push(other),
+        }
+    }
+    joined
+}
+
+/// The logical working directory: `PWD` as `cd` left it, which may run
+/// through symlinks, as long as it still names the directory the shell is
+/// in. Otherwise, say after the directory was moved, the physical one.
+fn logical_cwd() -> io::Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    if let Some(pwd) = env::var_os("PWD").map(PathBuf::from) {
+        let same_directory = match (std::fs::metadata(&pwd), std::fs::metadata(".")) {
+            (Ok(pwd), Ok(dot)) => pwd.dev() == dot.dev() && pwd.ino() == dot.ino(),
+            _ => false,
+        };
+        let tidy = !pwd
+            .components()
+            .any(|component| component == std::path::Component::ParentDir);
+        if pwd.is_absolute() && tidy && same_directory {
+            return Ok(pwd);
+        }
+    }
+    env::current_dir()
+}
+
+/// What `pwd [-L|-P]` prints: the logical directory, or with `-P` the
+/// physical one with every symlink resolved.
+fn pwd_path(args: &[String]) -> Result<PathBuf, ShellError> {
+    let mut physical = false;
+    for arg in args {
+        match arg.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(ShellError::builtin(
+                    "pwd",
+                    format!("{}: invalid option", flag),
+                ));
+            }
+            _ => {}
+        }
+    }
+    let path = if physical {
+        env::current_dir()
+    } else {
+        logical_cwd()
+    };
+    path.map_err(|_| ShellError::builtin("pwd", "error getting current directory"))
+}
+
+/// Quote `value` so the shell reads it back as one literal word.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Split the leading `NAME=value` words off `env`'s arguments, returning
+/// them as pairs along with the command that follows.
+fn split_env_assignments(args: &[String]) -> (Vec<(String, String)>, &[String]) {
+    let count = args.iter().take_while(|arg| arg.contains('=')).count();
+    let assignments = args[..count]
+        .iter()
+        .filter_map(|arg| arg.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    (assignments, &args[count..])
+}
+
+/// `env` as a pipeline stage: list the environment with the assignments
+/// applied, or run the command with them and return what it printed.
+fn env_output(args: &[String], stdin: Option<File>) -> (Vec<u8>, i32) {
+    let (assignments, command) = split_env_assignments(args);
+    let Some((name, args)) = command.split_first() else {
+        let mut vars: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| !assignments.iter().any(|(assigned, _)| assigned == name))
+            .collect();
+        vars.extend(assignments);
+
+        let mut output = Vec::new();
+        for (name, value) in vars {
+            writeln!(output, "{}={}", name, value).ok();
+        }
+        return (output, 0);
+    };
+
+    let Some(path) = find_in_path(name) else {
+        eprintln!("{}", ShellError::CommandNotFound(name.clone()));
+        return (Vec::new(), 127);
+    };
+    let mut command = Command::new(path);
+    command
+        .arg0(name)
+        .args(args)
+        .envs(assignments)
+        .stderr(Stdio::inherit());
+    command.stdin(stdin.map_or_else(Stdio::null, Stdio::from));
+    match command.output() {
+        Ok(output) => (output.stdout, exit_status_code(output.status)),
+        Err(err) => {
+            let err = spawn_error(name, err);
+            eprintln!("{}", err);
+            (Vec::new(), err.exit_code())
+        }
+    }
+}
+
+/// Exported variables as sorted `NAME=value` lines.
+fn export_listing() -> Vec<u8> {
+    let mut vars: Vec<(String, String)> = env::vars().collect();
+    vars.sort();
+
+    let mut output = Vec::new();
+    for (name, value) in vars {
+        writeln!(output, "{}={}", name, value).ok();
+    }
+    output
+}
+
+/// Shell and environment variables as sorted `NAME=value` lines, with
+/// values quoted where the shell would otherwise split or expand them.
+fn set_listing(variables: &HashMap<String, String>) -> Vec<u8> {
+    let mut vars: Vec<(String, String)> = env::vars().collect();
+    vars.extend(variables.iter().map(|(n, v)| (n.clone(), v.clone())));
+    vars.sort();
+
+    let mut output = Vec::new();
+    for (name, value) in vars {
+        let plain = !value.is_empty()
+            && value
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || "_-./:,+@%=".contains(ch));
+        if plain {
+            writeln!(output, "{}={}", name, value).ok();
+        } else {
+            writeln!(output, "{}={}", name, single_quote(&value)).ok();
+        }
+    }
+    output
+}
+
+fn is_builtin(cmd: &str) -> bool {
+    matches!(
+        cmd,
+        "echo"
+            | "exit"
+            | "type"
+            | "pwd"
+            | "cd"
+            | "history"
+            | "builtin"
+            | "kill"
+            | "repeat"
+            | "loop"
+            | "export"
+            | "unset"
+            | "compgen"
+            | "alias"
+            | "unalias"
+            | "source"
+            | "."
+            | "return"
+            | "set"
+            | "jobs"
+            | "fg"
+            | "grep"
+            | "wc"
+            | "which"
+            | "true"
+            | "false"
+            | "test"
+            | "["
+            | "env"
+    ) || (cfg!(feature = "ls") && cmd == "ls")
+}
+
+/// Build the bytes `echo` prints. With `-z` the arguments are separated and
+/// terminated by NUL bytes (for `xargs -0`) instead of spaces and a newline.
+fn echo_output(args: &[String]) -> Vec<u8> {
+    let (args, separator, terminator) = match args.split_first() {
+        Some((flag, rest)) if flag == "-z" => (rest, b'\0', b'\0'),
+        _ => (args, b' ', b'\n'),
+    };
+
+    let mut output = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            output.push(separator);
+        }
+        output.extend_from_slice(arg.as_bytes());
+    }
+    output.push(terminator);
+    output
+}
+
+/// Run a builtin as a pipeline stage (or where its output is needed as
+/// bytes), returning what it printed and its exit status.
+fn execute_builtin(cmd: &str, args: &[String], mut stdin: Option<File>) -> (Vec<u8>, i32) {
+    use std::io::Read;
+
+    let mut output = Vec::new();
+    let mut status = 0;
+
+    match cmd {
+        "echo" => {
+            output = echo_output(args);
+        }
+        "type" => {
+            if let Some(arg) = args.first() {
+                let result = if is_builtin(arg) {
+                    format!("{} is a shell builtin\n", arg)
+                } else if let Some(path) = find_in_path(arg) {
+                    format!("{} is {}\n", arg, path)
+                } else {
+                    format!("{}: not found\n", arg)
+                };
+                output.extend_from_slice(result.as_bytes());
+            }
+        }
+        "pwd" => match pwd_path(args) {
+            Ok(path) => {
+                output.extend_from_slice(path.display().to_string().as_bytes());
+                output.push(b'\n');
+            }
+            Err(err) => status = report(err),
+        },
+        "kill" => {
+            (output, status) = execute_kill(args);
+        }
+        "compgen" => match compgen_matches(args) {
+            Ok(matches) => {
+                for candidate in matches {
+                    output.extend_from_slice(candidate.as_bytes());
+                    output.push(b'\n');
+                }
+            }
+            Err(err) => eprintln!("{}", err),
+        },
+        // Pipeline stages can't change the shell's environment, so only the
+        // listing form does anything here
+        "export" if args.is_empty() => {
+            output = export_listing();
+        }
+        "repeat" => {
+            // Inside a pipeline only builtins can be repeated in-process
+            if let [count, name, rest @ ..] = args {
+                match count.parse::<usize>() {
+                    Ok(count) if is_builtin(name) => {
+                        for _ in 0..count {
+                            let (repeated, repeated_status) = execute_builtin(name, rest, None);
+                            output.extend(repeated);
+                            status = repeated_status;
                         }
-                    } else {
-                        // Outside quotes, backslash escapes any character
-                        chars.next(); // consume the next character
-                        current_arg.push(next_ch);
                     }
+                    Ok(_) => eprintln!("repeat: {}: only builtins repeat inside a pipeline", name),
+                    Err(_) => eprintln!("repeat: {}: invalid count", count),
                 }
             }
-            '\'' if !in_double_quote => {
-                in_single_quote = !in_single_quote;
+        }
+        "builtin" => {
+            if let Some((name, rest)) = args.split_first() {
+                if is_builtin(name) {
+                    return execute_builtin(name, rest, stdin);
+                }
+                eprintln!("builtin: {}: not a shell builtin", name);
             }
-            '"' if !in_single_quote => {
-                in_double_quote = !in_double_quote;
+        }
+        "grep" => {
+            (output, status) = grep_output(args, &mut stdin);
+        }
+        "wc" => {
+            (output, status) = wc_output(args, &mut stdin);
+        }
+        "which" => {
+            (output, status) = which_output(args);
+        }
+        "test" | "[" => {
+            status = test_status(cmd, args);
+        }
+        "env" => {
+            (output, status) = env_output(args, stdin.take());
+        }
+        // A pipeline stage never writes to the terminal, so one name a line
+        #[cfg(feature = "ls")]
+        "ls" => {
+            (output, status) = ls::ls_output(args, None);
+        }
+        "true" => {}
+        "false" => {
+            status = 1;
+        }
+        _ => {}
+    }
+
+    // Consume stdin if provided (to avoid broken pipe errors)
+    if let Some(mut stdin_reader) = stdin {
+        let mut _buffer = Vec::new();
+        stdin_reader.read_to_end(&mut _buffer).ok();
+    }
+
+    (output, status)
+}
+
+/// `grep [-ivncqF] PATTERN [FILE...]`: the lines matching the regular
+/// expression PATTERN (a fixed string with `-F`). `-i` ignores case, `-v`
+/// selects the lines that don't match, `-n` numbers them, `-c` only counts
+/// them and `-q` prints nothing. With several files each line is prefixed
+/// with its file name. The status is 0 if any line was selected, 1 if none
+/// was and 2 on an error.
+fn grep_output(args: &[String], stdin: &mut Option<File>) -> (Vec<u8>, i32) {
+    let (mut ignore_case, mut invert, mut number, mut count_only, mut quiet, mut fixed) =
+        (false, false, false, false, false, false);
+    let mut args = args;
+    while let Some((flag, rest)) = args.split_first() {
+        if flag == "--" {
+            args = rest;
+            break;
+        }
+        let Some(letters) = flag.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+            break;
+        };
+        for letter in letters.chars() {
+            match letter {
+                'i' => ignore_case = true,
+                'v' => invert = true,
+                'n' => number = true,
+                'c' => count_only = true,
+                'q' => quiet = true,
+                'F' => fixed = true,
+                _ => {
+                    eprintln!("grep: -{}: invalid option", letter);
+                    return (Vec::new(), 2);
+                }
+            }
+        }
+        args = rest;
+    }
+    let Some((pattern, files)) = args.split_first() else {
+        eprintln!("grep: usage: grep [-ivncqF] PATTERN [FILE...]");
+        return (Vec::new(), 2);
+    };
+
+    let source = if fixed {
+        regex::escape(pattern)
+    } else {
+        pattern.clone()
+    };
+    let Ok(regex) = regex::bytes::RegexBuilder::new(&source)
+        .case_insensitive(ignore_case)
+        .build()
+    else {
+        eprintln!("grep: {}: invalid pattern", pattern);
+        return (Vec::new(), 2);
+    };
+
+    let Some((inputs, all_read)) = read_inputs("grep", files, stdin) else {
+        return (Vec::new(), 130);
+    };
+    let show_names = files.len() > 1;
+    let mut output = Vec::new();
+    let mut selected_any = false;
+    for input in &inputs {
+        let prefix = match &input.name {
+            Some(name) if show_names => format!("{}:", name),
+            _ => String::new(),
+        };
+        let mut selected = 0;
+        for (index, line) in input.contents.split_inclusive(|&b| b == b'\n').enumerate() {
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            if regex.is_match(text) == invert {
+                continue;
+            }
+            selected += 1;
+            if quiet || count_only {
+                continue;
+            }
+            output.extend_from_slice(prefix.as_bytes());
+            if number {
+                write!(output, "{}:", index + 1).ok();
+            }
+            output.extend_from_slice(text);
+            output.push(b'\n');
+        }
+        if count_only && !quiet {
+            writeln!(output, "{}{}", prefix, selected).ok();
+        }
+        selected_any |= selected > 0;
+    }
+
+    // An unreadable file is an error, unless -q already found a match
+    let status = if selected_any && (all_read || quiet) {
+        0
+    } else if all_read {
+        1
+    } else {
+        2
+    };
+    (output, status)
+}
+
+/// One input of a filter builtin.
+struct FilterInput {
+    /// The file it was read from; `None` for stdin
+    name: Option<String>,
+    contents: Vec<u8>,
+}
+
+/// The inputs of a filter builtin: the named files, or `stdin` (the shell's
+/// own when outside a pipeline) when there are none. Files that can't be
+/// read are reported and skipped, and make the flag returned with them false.
+/// `None` means Ctrl-C interrupted a read of the shell's stdin.
+fn read_inputs(
+    builtin: &str,
+    files: &[String],
+    stdin: &mut Option<File>,
+) -> Option<(Vec<FilterInput>, bool)> {
+    use std::io::Read;
+
+    if files.is_empty() {
+        let mut contents = Vec::new();
+        match stdin.take() {
+            Some(mut stdin) => {
+                stdin.read_to_end(&mut contents).ok();
+            }
+            None => read_shell_stdin(&mut contents)?,
+        };
+        return Some((
+            vec![FilterInput {
+                name: None,
+                contents,
+            }],
+            true,
+        ));
+    }
+
+    let mut inputs = Vec::new();
+    let mut all_read = true;
+    for file in files {
+        match std::fs::read(file) {
+            Ok(contents) => inputs.push(FilterInput {
+                name: Some(file.clone()),
+                contents,
+            }),
+            Err(err) => {
+                eprintln!(
+                    "{}",
+                    ShellError::builtin(builtin, format!("{}: {}", file, error::describe(&err)))
+                );
+                all_read = false;
+            }
+        }
+    }
+    Some((inputs, all_read))
+}
+
+/// Set when SIGINT arrives during `read_shell_stdin`.
+static STDIN_READ_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Read the shell's own stdin to EOF, usually the terminal, as `grep` or
+/// `wc` with no file do. The shell's SIGINT handler restarts interrupted
+/// reads, so for the duration one that doesn't is installed; `None` means
+/// Ctrl-C ended the read.
+fn read_shell_stdin(contents: &mut Vec<u8>) -> Option<()> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+    use std::io::Read;
+
+    extern "C" fn interrupted(_: i32) {
+        STDIN_READ_INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+
+    STDIN_READ_INTERRUPTED.store(false, Ordering::Relaxed);
+    let action = SigAction::new(
+        SigHandler::Handler(interrupted),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only stores to an atomic, and the previous
+    // action is put back before returning
+    let previous = unsafe { sigaction(Signal::SIGINT, &action) }.ok();
+
+    let mut stdin = io::stdin().lock();
+    let mut buffer = [0; 8192];
+    while !STDIN_READ_INTERRUPTED.load(Ordering::Relaxed) {
+        match stdin.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => contents.extend_from_slice(&buffer[..len]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+
+    if let Some(previous) = previous {
+        // SAFETY: restores the action that was in place before
+        unsafe { sigaction(Signal::SIGINT, &previous) }.ok();
+    }
+    (!STDIN_READ_INTERRUPTED.load(Ordering::Relaxed)).then_some(())
+}
+
+/// `wc [-lwc] [FILE...]`: the line, word and byte counts of each file (or
+/// stdin), or just the ones asked for, with a total line for several files.
+/// Columns are aligned like GNU wc: as wide as the total byte count needs,
+/// at least 7 for stdin, and unpadded for a single count of a single input.
+fn wc_output(args: &[String], stdin: &mut Option<File>) -> (Vec<u8>, i32) {
+    let mut selected = Vec::new();
+    let mut args = args;
+    while let Some((flag, rest)) = args.split_first() {
+        if flag == "--" {
+            args = rest;
+            break;
+        }
+        let Some(letters) = flag.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+            break;
+        };
+        for letter in letters.chars() {
+            if !matches!(letter, 'l' | 'w' | 'c') {
+                eprintln!("wc: -{}: invalid option", letter);
+                return (Vec::new(), 1);
+            }
+            selected.push(letter);
+        }
+        args = rest;
+    }
+    let files = args;
+
+    let Some((inputs, all_read)) = read_inputs("wc", files, stdin) else {
+        return (Vec::new(), 130);
+    };
+    let columns: Vec<char> = ['l', 'w', 'c']
+        .into_iter()
+        .filter(|column| selected.is_empty() || selected.contains(column))
+        .collect();
+
+    let mut rows: Vec<(Vec<usize>, Option<&str>)> = inputs
+        .iter()
+        .map(|input| {
+            let counts = columns
+                .iter()
+                .map(|column| match column {
+                    'l' => input.contents.iter().filter(|&&b| b == b'\n').count(),
+                    'w' => input
+                        .contents
+                        .split(|b| b.is_ascii_whitespace())
+                        .filter(|word| !word.is_empty())
+                        .count(),
+                    _ => input.contents.len(),
+                })
+                .collect();
+            (counts, input.name.as_deref())
+        })
+        .collect();
+    if files.len() > 1 {
+        let totals = (0..columns.len())
+            .map(|index| rows.iter().map(|(counts, _)| counts[index]).sum())
+            .collect();
+        rows.push((totals, Some("total")));
+    }
+
+    let total_bytes: usize = inputs.iter().map(|input| input.contents.len()).sum();
+    let width = if columns.len() == 1 && rows.len() == 1 {
+        0
+    } else if files.is_empty() {
+        7
+    } else {
+        total_bytes.to_string().len()
+    };
+
+    let mut output = Vec::new();
+    for (counts, name) in rows {
+        let mut fields: Vec<String> = counts
+            .iter()
+            .map(|count| format!("{:>width$}", count, width = width))
+            .collect();
+        fields.extend(name.map(str::to_string));
+        writeln!(output, "{}", fields.join(" ")).ok();
+    }
+    (output, if all_read { 0 } else { 1 })
+}
+
+/// Look up a signal by number or by name, with or without the `SIG` prefix.
+fn parse_signal(spec: &str) -> Option<Signal> {
+    if let Ok(number) = spec.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = spec.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    Signal::iterator().find(|sig| &sig.as_str()[3..] == name)
+}
+
+/// Name of a signal without the `SIG` prefix, e.g. `KILL`.
+fn signal_name(sig: Signal) -> &'static str {
+    &sig.as_str()[3..]
+}
+
+/// `compgen [-c] [-f] [-W WORDS] [--] [WORD]` lists the completions of WORD
+/// that the chosen generators produce: commands, file names, or the words of
+/// a whitespace-separated list. Matching is the same as for interactive
+/// completion.
+fn compgen_matches(args: &[String]) -> Result<Vec<String>, ShellError> {
+    let mut commands = false;
+    let mut files = false;
+    let mut wordlist = None;
+
+    let mut args = args.iter();
+    let mut word = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => commands = true,
+            "-f" => files = true,
+            "-W" => {
+                let words = args.next().ok_or_else(|| {
+                    ShellError::builtin("compgen", "-W: option requires an argument")
+                })?;
+                wordlist = Some(words.as_str());
+            }
+            "--" => {
+                word = args.next();
+                break;
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(ShellError::builtin(
+                    "compgen",
+                    format!("{}: invalid option", flag),
+                ));
+            }
+            _ => {
+                word = Some(arg);
+                break;
+            }
+        }
+    }
+    let word = word.map(String::as_str).unwrap_or("");
+
+    let mut matches = Vec::new();
+    if let Some(words) = wordlist {
+        matches.extend(
+            words
+                .split_whitespace()
+                .filter(|candidate| candidate.starts_with(word))
+                .map(str::to_string),
+        );
+    }
+    // Candidates carry the suffix completion would insert; compgen prints bare names
+    if commands {
+        matches.extend(
+            complete_command(word, path_commands(&search_path()).keys())
+                .into_iter()
+                .map(|c| c.replacement.trim_end().to_string()),
+        );
+    }
+    if files {
+        matches.extend(complete_path(word).into_iter().map(|c| {
+            let name = c.replacement.trim_end();
+            name.strip_suffix('/').unwrap_or(name).to_string()
+        }));
+    }
+    Ok(matches)
+}
+
+/// `kill -l [SIG]` lists or translates signals; `kill [-s SIG | -SIG] PID...`
+/// sends a signal (TERM by default) to each process. The status is 1 if
+/// any part of that failed.
+fn execute_kill(args: &[String]) -> (Vec<u8>, i32) {
+    let mut output = Vec::new();
+    let mut status = 0;
+
+    if args.first().map(String::as_str) == Some("-l") {
+        if args.len() == 1 {
+            let entries: Vec<String> = Signal::iterator()
+                .map(|sig| format!("{:2}) SIG{}", sig as i32, signal_name(sig)))
+                .collect();
+            for row in entries.chunks(5) {
+                output.extend_from_slice(row.join("\t").as_bytes());
+                output.push(b'\n');
             }
-            ' ' | '\t' if !in_single_quote && !in_double_quote => {
-                if !current_arg.is_empty() {
-                    args.push(current_arg.clone());
-                    current_arg.clear();
+            return (output, 0);
+        }
+
+        for spec in &args[1..] {
+            match spec.parse::<i32>() {
+                // Exit statuses above 128 name the signal that killed a process
+                Ok(number) => match parse_signal(&(number & 0x7f).to_string()) {
+                    Some(sig) => writeln!(output, "{}", signal_name(sig)).ok(),
+                    None => {
+                        eprintln!("kill: {}: invalid signal specification", spec);
+                        status = 1;
+                        None
+                    }
+                },
+                Err(_) => match parse_signal(spec) {
+                    Some(sig) => writeln!(output, "{}", sig as i32).ok(),
+                    None => {
+                        eprintln!("kill: {}: invalid signal specification", spec);
+                        status = 1;
+                        None
+                    }
+                },
+            };
+        }
+        return (output, status);
+    }
+
+    let mut signal = Signal::SIGTERM;
+    let mut pids = args;
+    if let Some(first) = args.first() {
+        let spec = if first == "-s" {
+            pids = args.get(2..).unwrap_or(&[]);
+            args.get(1).map(String::as_str)
+        } else if let Some(spec) = first.strip_prefix('-') {
+            pids = &args[1..];
+            Some(spec)
+        } else {
+            None
+        };
+        if let Some(spec) = spec {
+            match parse_signal(spec) {
+                Some(sig) => signal = sig,
+                None => {
+                    eprintln!("kill: {}: invalid signal specification", spec);
+                    return (output, 1);
                 }
             }
-            _ => {
-                current_arg.push(ch);
-            }
         }
     }
 
-    if !current_arg.is_empty() {
-        args.push(current_arg);
+    if pids.is_empty() {
+        eprintln!("kill: usage: kill [-s sigspec | -sigspec] pid ... or kill -l [sigspec]");
+        status = 1;
     }
 
-    args
-}
-
-fn parse_redirection(
-    parts: &[String],
-) -> (Vec<String>, Option<String>, bool, Option<String>, bool) {
-    let mut cmd_parts = Vec::new();
-    let mut stdout_file = None;
-    let mut stdout_append = false;
-    let mut stderr_file = None;
-    let mut stderr_append = false;
-    let mut i = 0;
-
-    while i < parts.len() {
-        let part = &parts[i];
-
-        // Check for >> or 1>> (stdout append)
-        if part == ">>" || part == "1>>" {
-            if i + 1 < parts.len() {
-                stdout_file = Some(parts[i + 1].clone());
-                stdout_append = true;
-                i += 2;
-                continue;
-            }
-        } else if part == "2>>" {
-            // stderr append
-            if i + 1 < parts.len() {
-                stderr_file = Some(parts[i + 1].clone());
-                stderr_append = true;
-                i += 2;
-                continue;
-            }
-        } else if part == ">" || part == "1>" {
-            // stdout overwrite
-            if i + 1 < parts.len() {
-                stdout_file = Some(parts[i + 1].clone());
-                stdout_append = false;
-                i += 2;
-                continue;
+    for pid in pids {
+        match pid.parse::<i32>() {
+            Ok(pid_num) => {
+                if let Err(err) = nix::sys::signal::kill(Pid::from_raw(pid_num), signal) {
+                    eprintln!("kill: ({}) - {}", pid, err.desc());
+                    status = 1;
+                }
             }
-        } else if part == "2>" {
-            // stderr overwrite
-            if i + 1 < parts.len() {
-                stderr_file = Some(parts[i + 1].clone());
-                stderr_append = false;
-                i += 2;
-                continue;
+            Err(_) => {
+                eprintln!("kill: {}: arguments must be process or job IDs", pid);
+                status = 1;
             }
-        } else if part.starts_with(">>") && !part.starts_with("2>>") {
-            // Handle cases like >>file (no space)
-            stdout_file = Some(part[2..].to_string());
-            stdout_append = true;
-            i += 1;
-            continue;
-        } else if part.starts_with("1>>") {
-            // Handle cases like 1>>file (no space)
-            stdout_file = Some(part[3..].to_string());
-            stdout_append = true;
-            i += 1;
-            continue;
-        } else if part.starts_with("2>>") {
-            // Handle cases like 2>>file (no space)
-            stderr_file = Some(part[3..].to_string());
-            stderr_append = true;
-            i += 1;
-            continue;
-        } else if part.starts_with(">") && !part.starts_with("2>") {
-            // Handle cases like >file (no space)
-            stdout_file = Some(part[1..].to_string());
-            stdout_append = false;
-            i += 1;
-            continue;
-        } else if part.starts_with("1>") {
-            // Handle cases like 1>file (no space)
-            stdout_file = Some(part[2..].to_string());
-            stdout_append = false;
-            i += 1;
-            continue;
-        } else if part.starts_with("2>") {
-            // Handle cases like 2>file (no space)
-            stderr_file = Some(part[2..].to_string());
-            stderr_append = false;
-            i += 1;
-            continue;
         }
+    }
 
-        cmd_parts.push(part.clone());
-        i += 1;
+    (output, status)
+}
+
+/// Run a builtin pipeline stage, unless the shell already rendered its
+/// `listing` (see `Shell::stage_listing`); its input is then discarded.
+fn stage_output(
+    cmd: &str,
+    args: &[String],
+    stdin: Option<File>,
+    listing: Option<(Vec<u8>, i32)>,
+) -> (Vec<u8>, i32) {
+    match listing {
+        Some(listing) => {
+            if let Some(mut stdin) = stdin {
+                io::copy(&mut stdin, &mut io::sink()).ok();
+            }
+            listing
+        }
+        None => execute_builtin(cmd, args, stdin),
     }
+}
 
-    (
-        cmd_parts,
-        stdout_file,
-        stdout_append,
-        stderr_file,
-        stderr_append,
-    )
+/// Create an anonymous pipe, returning its (read, write) ends. Both ends are
+/// close-on-exec, so children spawned meanwhile don't hold a write end open.
+fn create_pipe() -> io::Result<(File, File)> {
+    let (read_fd, write_fd) = nix::unistd::pipe().map_err(io::Error::from)?;
+    // SAFETY: pipe(2) just returned these descriptors and nothing else owns them
+    let (read_end, write_end) = unsafe {
+        (
+            OwnedFd::from_raw_fd(read_fd),
+            OwnedFd::from_raw_fd(write_fd),
+        )
+    };
+    // `try_clone` duplicates with FD_CLOEXEC set; the originals close here
+    Ok((
+        File::from(read_end.try_clone()?),
+        File::from(write_end.try_clone()?),
+    ))
 }
 
-fn is_builtin(cmd: &str) -> bool {
-    matches!(cmd, "echo" | "exit" | "type" | "pwd" | "cd" | "history")
+/// A pipe that reads back `data`, written from a thread so a large
+/// here-document can't block the shell.
+fn data_reader(data: String) -> io::Result<File> {
+    let (reader, mut writer) = create_pipe()?;
+    std::thread::spawn(move || {
+        writer.write_all(data.as_bytes()).ok();
+    });
+    Ok(reader)
 }
 
-fn execute_builtin(
-    cmd: &str,
-    args: &[String],
-    stdin: Option<std::process::ChildStdout>,
-) -> Vec<u8> {
-    use std::io::Read;
+/// A pipeline stage with its own redirections opened: where it reads and
+/// writes instead of the pipe, or the terminal at either end.
+struct StageStreams {
+    cmd_parts: Vec<String>,
+    stdin: Option<File>,
+    stdout: Option<File>,
+    stderr: Option<File>,
+    /// `2>&1` with stdout left on the pipe or the terminal
+    stderr_to_stdout: bool,
+}
 
-    let mut output = Vec::new();
+/// Split a pipeline stage from its redirections and open them, the way a
+/// child's would be before it runs.
+fn stage_streams(stage: &[Arg]) -> Result<StageStreams, ShellError> {
+    let (stage, close_stdout, close_stderr) = take_fd_closes(stage);
+    let redirections = parse_redirection(&stage);
+    // Every stage of a pipeline needs a command
+    let Some(cmd) = redirections.cmd_parts.first() else {
+        return Err(ShellError::SyntaxError("|".to_string()));
+    };
+    let fail = |err| spawn_error(cmd, err);
 
-    match cmd {
-        "echo" => {
-            let text = args.join(" ");
-            output.extend_from_slice(text.as_bytes());
-            output.push(b'\n');
-        }
-        "type" => {
-            if let Some(arg) = args.first() {
-                let result = if is_builtin(arg) {
-                    format!("{} is a shell builtin\n", arg)
-                } else if let Some(path) = find_in_path(arg) {
-                    format!("{} is {}\n", arg, path)
-                } else {
-                    format!("{}: not found\n", arg)
-                };
-                output.extend_from_slice(result.as_bytes());
-            }
-        }
-        "pwd" => {
-            if let Ok(path) = env::current_dir() {
-                output.extend_from_slice(path.display().to_string().as_bytes());
-                output.push(b'\n');
-            }
+    let stdin = match (&redirections.stdin_file, &redirections.stdin_data) {
+        (Some(path), _) => Some(open_input(path)?),
+        (None, Some(data)) => Some(data_reader(data.clone()).map_err(fail)?),
+        (None, None) => None,
+    };
+    let stdout = match &redirections.stdout_file {
+        Some(path) => Some(open_redirect(path, redirections.stdout_append)?),
+        None if close_stdout => Some(open_redirect(NULL_DEVICE, false)?),
+        None if redirections.stdout_to_stderr => {
+            let stderr_copy = io::stderr().as_fd().try_clone_to_owned().map_err(fail)?;
+            Some(File::from(stderr_copy))
         }
-        _ => {}
-    }
+        None => None,
+    };
+    // `> log 2>&1` shares one open file
+    let stderr = match &redirections.stderr_file {
+        Some(path) if redirections.stdout_file.as_ref() == Some(path) => stdout
+            .as_ref()
+            .map(File::try_clone)
+            .transpose()
+            .map_err(fail)?,
+        Some(path) => Some(open_redirect(path, redirections.stderr_append)?),
+        None if close_stderr => Some(open_redirect(NULL_DEVICE, false)?),
+        None => None,
+    };
 
-    // Consume stdin if provided (to avoid broken pipe errors)
-    if let Some(mut stdin_reader) = stdin {
-        let mut _buffer = Vec::new();
-        stdin_reader.read_to_end(&mut _buffer).ok();
-    }
+    Ok(StageStreams {
+        stdin,
+        stdout,
+        stderr,
+        stderr_to_stdout: redirections.stderr_to_stdout,
+        cmd_parts: redirections.cmd_parts,
+    })
+}
 
-    output
+/// Kill the stages already started when a later one can't run.
+fn abandon_pipeline(children: Vec<std::process::Child>, err: ShellError) -> ShellError {
+    for mut child in children {
+        child.kill().ok();
+    }
+    err
 }
 
-fn execute_pipeline(parts: &[String]) {
+fn execute_pipeline(
+    parts: &[Arg],
+    out: &mut impl Write,
+    listings: &[Option<(Vec<u8>, i32)>],
+) -> Result<i32, ShellError> {
     use std::process::Stdio;
 
     // Split commands by pipe operator
-    let mut commands: Vec<Vec<String>> = Vec::new();
+    let mut commands: Vec<Vec<Arg>> = Vec::new();
     let mut current_cmd = Vec::new();
 
     for part in parts {
-        if part == "|" {
-            if !current_cmd.is_empty() {
-                commands.push(current_cmd.clone());
-                current_cmd.clear();
+        if part.is_operator("|") {
+            // Every stage of a pipeline needs a command
+            if current_cmd.is_empty() {
+                return Err(ShellError::SyntaxError("|".to_string()));
             }
+            commands.push(current_cmd.clone());
+            current_cmd.clear();
         } else {
             current_cmd.push(part.clone());
         }
     }
-    if !current_cmd.is_empty() {
-        commands.push(current_cmd);
-    }
-
-    if commands.is_empty() {
-        return;
+    if current_cmd.is_empty() {
+        return Err(ShellError::SyntaxError("|".to_string()));
     }
+    commands.push(current_cmd);
 
     // Track child processes
     let mut children: Vec<std::process::Child> = Vec::new();
-    let mut prev_stdout: Option<std::process::ChildStdout> = None;
+    let mut prev_stdout: Option<File> = None;
+    // A builtin in the last stage runs in-process and reports its own status
+    let mut last_builtin_status = None;
+    // Threads running the builtin stages before the last one
+    let mut writers = Vec::new();
 
-    for (i, cmd_parts) in commands.iter().enumerate() {
-        if cmd_parts.is_empty() {
-            continue;
-        }
-
-        let cmd = cmd_parts[0].as_str();
-        let args = &cmd_parts[1..];
+    for (i, stage) in commands.iter().enumerate() {
+        let streams = match stage_streams(stage) {
+            Ok(streams) => streams,
+            Err(err) => return Err(abandon_pipeline(children, err)),
+        };
+        let (cmd, args) = streams
+            .cmd_parts
+            .split_first()
+            .map(|(cmd, args)| (cmd.as_str(), args))
+            .unwrap_or_default();
+        let fail = |err| spawn_error(cmd, err);
         let is_last = i == commands.len() - 1;
 
-        if is_builtin(cmd) {
-            // Handle built-in command
-            let output = execute_builtin(cmd, args, prev_stdout.take());
+        // `<`, a here-document or a here-string replaces what the previous
+        // stage sends
+        if let Some(stdin) = streams.stdin {
+            prev_stdout = Some(stdin);
+        }
+        // Between stages the output goes into a pipe, unless the stage sends
+        // it elsewhere; the next stage then reads nothing
+        let (next_stdin, stdout) = if is_last {
+            (None, streams.stdout)
+        } else {
+            let (reader, writer) = create_pipe().map_err(fail)?;
+            (Some(reader), Some(streams.stdout.unwrap_or(writer)))
+        };
+        if is_last {
+            // The last stage may write straight to the terminal, so anything
+            // still buffered must go out first
+            out.flush().ok();
+        }
+        // `2>&1` sends this stage's stderr wherever its stdout goes
+        let stderr = match streams.stderr {
+            Some(file) => Some(file),
+            None if streams.stderr_to_stdout => Some(match &stdout {
+                Some(file) => file.try_clone().map_err(fail)?,
+                None => File::from(io::stdout().as_fd().try_clone_to_owned().map_err(fail)?),
+            }),
+            None => None,
+        };
 
-            if is_last {
-                // Last command: write to stdout
-                io::stdout().write_all(&output).unwrap();
-            } else {
-                // Not last: need to create a pipe for next command
-                // Use 'cat' as a pipe helper to convert Vec<u8> to ChildStdout
-                let mut child_cmd = Command::new("cat");
-                child_cmd.stdin(Stdio::piped());
-                child_cmd.stdout(Stdio::piped());
-
-                let mut child = match child_cmd.spawn() {
-                    Ok(c) => c,
-                    Err(_) => {
-                        eprintln!("Failed to create pipe for builtin");
-                        return;
+        if is_builtin(cmd) {
+            let listing = listings.get(i).cloned().flatten();
+            let stdin = prev_stdout.take();
+            match stdout {
+                // Not last: run on its own thread, reading the previous stage
+                // and writing into a pipe, so neither side waits on the other
+                Some(mut writer) if !is_last && stderr.is_none() => {
+                    let (cmd, args) = (cmd.to_string(), args.to_vec());
+                    writers.push(std::thread::spawn(move || {
+                        writer
+                            .write_all(&stage_output(&cmd, &args, stdin, listing).0)
+                            .ok();
+                    }));
+                }
+                // The last stage runs in-process, and so does one with its
+                // own stderr, since that belongs to the whole process
+                stdout => {
+                    let (output, status) =
+                        with_stderr(stderr.as_ref(), || stage_output(cmd, args, stdin, listing))
+                            .map_err(fail)?;
+                    match stdout {
+                        Some(mut writer) if !is_last => {
+                            writers.push(std::thread::spawn(move || {
+                                writer.write_all(&output).ok();
+                            }))
+                        }
+                        Some(mut file) => {
+                            file.write_all(&output).ok();
+                        }
+                        None => {
+                            out.write_all(&output).ok();
+                        }
+                    }
+                    if is_last {
+                        last_builtin_status = Some(status);
                     }
-                };
-
-                // Write builtin output to cat's stdin
-                if let Some(mut stdin) = child.stdin.take() {
-                    stdin.write_all(&output).ok();
                 }
-
-                prev_stdout = child.stdout.take();
-                children.push(child);
             }
         } else {
             // Handle external command
             let Some(cmd_path) = find_in_path(cmd) else {
-                eprintln!("{}: command not found", cmd);
-                // Kill previous processes
-                for mut child in children {
-                    child.kill().ok();
-                }
-                return;
+                let err = ShellError::CommandNotFound(cmd.to_string());
+                return Err(abandon_pipeline(children, err));
             };
 
             let mut command = Command::new(cmd_path);
             command.arg0(cmd).args(args);
 
             // Setup stdin from previous command
-            if let Some(stdout) = prev_stdout.take() {
-                command.stdin(Stdio::from(stdout));
+            if let Some(stdin) = prev_stdout.take() {
+                command.stdin(Stdio::from(stdin));
+            }
+            if let Some(stdout) = stdout {
+                command.stdout(Stdio::from(stdout));
+            }
+            if let Some(stderr) = stderr {
+                command.stderr(Stdio::from(stderr));
             }
 
-            // Setup stdout for next command or terminal
-            if !is_last {
-                command.stdout(Stdio::piped());
+            match command.spawn() {
+                Ok(child) => children.push(child),
+                Err(err) => return Err(abandon_pipeline(children, fail(err))),
             }
+            // Drop our copy of the write ends so the next stage sees EOF
+            drop(command);
+        }
 
-            let mut child = match command.spawn() {
-                Ok(c) => c,
-                Err(_) => {
-                    eprintln!("Failed to execute {}", cmd);
-                    // Kill previous processes
-                    for mut child in children {
-                        child.kill().ok();
-                    }
-                    return;
-                }
-            };
+        prev_stdout = next_stdin;
+    }
+
+    for writer in writers {
+        writer.join().ok();
+    }
+
+    // Wait for all children to finish; the pipeline's status is the last one's
+    let mut status = 0;
+    for mut child in children {
+        if let Ok(exit) = child.wait() {
+            status = exit_status_code(exit);
+        }
+    }
+    Ok(last_builtin_status.unwrap_or(status))
+}
+
+/// Match `text` against a shell glob `pattern` supporting `*`, `?`, `[...]`
+/// (with `!`/`^` negation and ranges) and backslash escapes.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_from(&pattern, &text)
+}
 
-            // Save stdout for next command if not last
-            if !is_last {
-                prev_stdout = child.stdout.take();
+fn glob_match_from(pattern: &[char], text: &[char]) -> bool {
+    let Some(&first) = pattern.first() else {
+        return text.is_empty();
+    };
+
+    match first {
+        '*' => (0..=text.len()).any(|skip| glob_match_from(&pattern[1..], &text[skip..])),
+        '?' => !text.is_empty() && glob_match_from(&pattern[1..], &text[1..]),
+        '[' => match (
+            text.first(),
+            match_bracket(&pattern[1..], text.first().copied()),
+        ) {
+            (Some(_), Some((true, consumed))) => {
+                glob_match_from(&pattern[1 + consumed..], &text[1..])
             }
+            (Some(_), Some((false, _))) => false,
+            // An unterminated bracket is matched literally
+            _ => text.first() == Some(&'[') && glob_match_from(&pattern[1..], &text[1..]),
+        },
+        '\\' if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match_from(&pattern[2..], &text[1..])
+        }
+        _ => text.first() == Some(&first) && glob_match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Evaluate a bracket expression (the part after `[`) against `ch`.
+/// Returns whether it matched and how many pattern chars were consumed,
+/// including the closing `]`, or `None` if the bracket is unterminated.
+fn match_bracket(pattern: &[char], ch: Option<char>) -> Option<(bool, usize)> {
+    let ch = ch?;
+    let mut i = 0;
+    let negate = matches!(pattern.first(), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
 
-            children.push(child);
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let c = pattern[i];
+        if c == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            if pattern[i] <= ch && ch <= pattern[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if c == ch {
+                matched = true;
+            }
+            i += 1;
         }
     }
 
-    // Wait for all children to finish
-    for mut child in children {
-        child.wait().ok();
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("popper-unit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn displays(candidates: &[Pair]) -> Vec<&str> {
+        candidates.iter().map(|c| c.display.as_str()).collect()
+    }
+
+    #[test]
+    fn path_completion_lists_directory_after_trailing_slash() {
+        let dir = scratch_dir("trailing-slash");
+        std::fs::create_dir_all(dir.join("src/nested")).unwrap();
+        File::create(dir.join("src/main.rs")).unwrap();
+        File::create(dir.join("src/.hidden")).unwrap();
+        File::create(dir.join("sibling.txt")).unwrap();
+
+        let word = format!("{}/src/", dir.display());
+        let candidates = complete_path(&word);
+        assert_eq!(displays(&candidates), ["main.rs", "nested/"]);
+        assert_eq!(candidates[0].replacement, format!("{}main.rs ", word));
+        assert_eq!(candidates[1].replacement, format!("{}nested/", word));
+
+        let word = format!("{}/src/ne", dir.display());
+        assert_eq!(displays(&complete_path(&word)), ["nested/"]);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn cd_completion_includes_cdpath_directories() {
+        let dir = scratch_dir("cdpath");
+        std::fs::create_dir_all(dir.join("projects/popper")).unwrap();
+        File::create(dir.join("projects/poem.txt")).unwrap();
+
+        let found = complete_path_in(&dir.join("projects"), "po");
+        assert_eq!(displays(&found), ["poem.txt", "popper/"]);
+        assert_eq!(found[1].replacement, "popper/");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn path_completion_keeps_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch_dir("non-utf8");
+        let name = std::ffi::OsStr::from_bytes(b"odd\xffname");
+        // Some filesystems refuse names that aren't UTF-8
+        if File::create(dir.join(name)).is_err() {
+            return;
+        }
+        File::create(dir.join("odder")).unwrap();
+
+        let word = format!("{}/odd", dir.display());
+        assert_eq!(
+            displays(&complete_path(&word)),
+            ["odder", "odd\u{fffd}name"]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn prompt_escapes_expand() {
+        let home = Some("/home/ada");
+        let cwd = Path::new("/home/ada/src/popper");
+        assert_eq!(expand_prompt("\\w> ", cwd, home), "~/src/popper> ");
+        assert_eq!(expand_prompt("[\\W]\\\\ \\q", cwd, home), "[popper]\\ \\q");
+        assert_eq!(
+            expand_prompt("\\w \\W", Path::new("/home/ada"), home),
+            "~ ~"
+        );
+        assert_eq!(
+            expand_prompt("\\w", Path::new("/home/adam"), home),
+            "/home/adam"
+        );
+
+        let dollar = if nix::unistd::geteuid().is_root() {
+            "#"
+        } else {
+            "$"
+        };
+        assert_eq!(expand_prompt("\\$ ", cwd, None), format!("{} ", dollar));
+        let host = nix::unistd::gethostname().unwrap();
+        let host = host.to_string_lossy();
+        assert_eq!(expand_prompt("\\H", cwd, None), host);
+        assert!(host.starts_with(&expand_prompt("\\h", cwd, None)));
+        assert!(!expand_prompt("\\u", cwd, None).contains('\\'));
+    }
+
+    #[test]
+    fn path_completion_looks_in_home_for_tilde_paths() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/home/me".to_string());
+        let base = Path::new("/work");
+
+        assert_eq!(
+            completion_dir(base, "~/src/", &lookup),
+            Path::new("/home/me/src/")
+        );
+        let root = nix::unistd::User::from_name("root").unwrap().unwrap().dir;
+        assert_eq!(completion_dir(base, "~root/", &lookup), root.join(""));
+        assert_eq!(
+            completion_dir(base, "src/", &lookup),
+            Path::new("/work/src/")
+        );
+        assert_eq!(
+            completion_dir(base, "~no-such-user/", &lookup),
+            Path::new("/work/~no-such-user/")
+        );
+    }
+
+    #[test]
+    fn path_cache_rescans_when_path_or_its_directories_change() {
+        let dir = scratch_dir("path-cache");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        for (bin, name) in [(&first, "tool-a"), (&second, "tool-b")] {
+            std::fs::create_dir(bin).unwrap();
+            let file = bin.join(name);
+            File::create(&file).unwrap();
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let mut cache = PathCache::default();
+        let path = first.display().to_string();
+        assert!(cache.refresh(&path));
+        assert!(cache.commands.keys().eq(["tool-a"]));
+        assert!(!cache.refresh(&path));
+
+        let path = format!("{}:{}", first.display(), second.display());
+        assert!(cache.refresh(&path));
+        assert!(cache.commands.keys().eq(["tool-a", "tool-b"]));
+        assert!(!cache.refresh(&path));
+
+        // A new file changes the directory's modification time
+        std::thread::sleep(Duration::from_millis(20));
+        let file = first.join("tool-c");
+        File::create(&file).unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(cache.refresh(&path));
+        assert!(cache.commands.keys().eq(["tool-a", "tool-b", "tool-c"]));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn here_doc_delimiters_are_found_outside_quotes() {
+        let specs = |line: &str| {
+            here_doc_specs(line)
+                .into_iter()
+                .map(|doc| (doc.delimiter, doc.strip_tabs, doc.expand))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(specs("cat <<EOF"), [("EOF".to_string(), false, true)]);
+        assert_eq!(
+            specs("cat <<- 'A B' | cat <<\\C>out"),
+            [
+                ("A B".to_string(), true, false),
+                ("C".to_string(), false, false)
+            ]
+        );
+        assert!(specs("echo '<<EOF' \"<<x\" # <<y").is_empty());
+        assert!(specs("cat <<< word").is_empty());
+    }
+
+    #[test]
+    fn completion_and_execution_resolve_names_the_same_way() {
+        let dir = scratch_dir("path-order");
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        for bin in [&first, &second] {
+            std::fs::create_dir(bin).unwrap();
+            let file = bin.join("tool");
+            File::create(&file).unwrap();
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let mut cache = PathCache::default();
+        for (winner, other) in [(&first, &second), (&second, &first)] {
+            let path = format!("{}:{}", winner.display(), other.display());
+            cache.refresh(&path);
+            let expected = winner.join("tool").display().to_string();
+            assert_eq!(cache.commands["tool"], expected);
+            assert_eq!(resolve_in_path(&path, "tool"), Some(expected));
+        }
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn quoted_command_words_complete_inside_their_quotes() {
+        let executables = ["ecat".to_string(), "pwdx".to_string()];
+        let replacements = |word: &str| {
+            complete_command_word(word, &executables)
+                .into_iter()
+                .map(|candidate| candidate.replacement)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(replacements("\"ec"), ["\"ecat\" ", "\"echo\" "]);
+        assert_eq!(replacements("'pw'"), ["'pwd'", "'pwdx' "]);
+        assert_eq!(replacements("ec"), ["ecat ", "echo "]);
+        assert!(replacements("\"nothing").is_empty());
+    }
+
+    #[test]
+    fn job_specs_complete_from_the_job_table() {
+        let mut shell = Shell::new();
+        shell.execute("sleep 5 &");
+        shell.execute("sleep 6 &");
+        shell.share_jobs();
+
+        let history = DefaultHistory::new();
+        let complete = |line: &str| {
+            let helper = shell.editor.helper().unwrap();
+            let (start, candidates) = helper
+                .complete(line, line.len(), &Context::new(&history))
+                .unwrap();
+            let candidates: Vec<(String, String)> = candidates
+                .into_iter()
+                .map(|pair| (pair.display, pair.replacement))
+                .collect();
+            (start, candidates)
+        };
+        let both = vec![
+            ("%1  sleep 5".to_string(), "%1 ".to_string()),
+            ("%2  sleep 6".to_string(), "%2 ".to_string()),
+        ];
+
+        assert_eq!(complete("fg "), (3, both.clone()));
+        assert_eq!(complete("kill -9 %"), (8, both));
+        assert_eq!(complete("bg %2").1.len(), 1);
+        // A plain argument to kill is a process, not a job
+        assert!(complete("kill ")
+            .1
+            .iter()
+            .all(|(_, spec)| !spec.starts_with('%')));
+
+        for job in &mut shell.jobs {
+            job.child.kill().ok();
+            job.child.wait().ok();
+        }
+    }
+
+    #[test]
+    fn highlighting_colors_operators_and_quotes() {
+        assert_eq!(
+            highlight_line("echo 'a b' | wc"),
+            "echo \x1b[32m'a b'\x1b[0m \x1b[1m|\x1b[0m wc"
+        );
+        assert_eq!(highlight_line("echo \"open"), "echo \x1b[31m\"open\x1b[0m");
+    }
+
+    #[test]
+    fn cd_completion_offers_only_directories() {
+        let dir = scratch_dir("cd-dirs");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        File::create(dir.join("draft.txt")).unwrap();
+        File::create(dir.join("dotfile")).unwrap();
+        std::os::unix::fs::symlink(dir.join("docs"), dir.join("dlink")).unwrap();
+
+        let word = format!("{}/d", dir.display());
+        let found = complete_directory(&word);
+        assert_eq!(displays(&found), ["dlink/", "docs/"]);
+        assert_eq!(found[1].replacement, format!("{}/docs/", dir.display()));
+        assert_eq!(complete_path(&word).len(), 4);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn history_word_designators_expand_from_previous_command() {
+        let previous = "ls -la /long/path".to_string();
+
+        let expanded = expand_history("cd !$", Some(&previous)).unwrap();
+        assert_eq!(expanded.as_deref(), Some("cd /long/path"));
+        let expanded = expand_history("echo !^ and !$", Some(&previous)).unwrap();
+        assert_eq!(expanded.as_deref(), Some("echo -la and /long/path"));
+
+        // Quoted or escaped designators stay literal
+        assert_eq!(
+            expand_history("echo '!$' \\!$", Some(&previous)).unwrap(),
+            None
+        );
+
+        let quoted = "ls -d \"my dir\"".to_string();
+        let expanded = expand_history("cd !$", Some(&quoted)).unwrap();
+        assert_eq!(expanded.as_deref(), Some("cd \"my dir\""));
+
+        assert!(expand_history("echo !$", None).is_err());
+        let single = "pwd".to_string();
+        assert!(expand_history("echo !^", Some(&single)).is_err());
+    }
+
+    #[test]
+    fn continuation_is_needed_for_open_quotes_and_trailing_backslash() {
+        assert_eq!(pending_continuation("echo hi"), None);
+        assert_eq!(pending_continuation("echo 'it''s'"), None);
+        assert_eq!(pending_continuation("echo \\\\"), None);
+        assert_eq!(
+            pending_continuation("echo hi \\"),
+            Some(Continuation::Backslash)
+        );
+        assert_eq!(
+            pending_continuation("echo \"a"),
+            Some(Continuation::Quote('"'))
+        );
+        assert_eq!(
+            pending_continuation("echo \"it's"),
+            Some(Continuation::Quote('"'))
+        );
+        assert_eq!(
+            pending_continuation("echo 'a \\"),
+            Some(Continuation::Quote('\''))
+        );
+    }
+
+    #[test]
+    fn loop_arguments_parse_interval_and_command() {
+        let words = |line: &str| parse_arguments(line);
+
+        let (interval, consumed) = parse_loop_args(&words("loop -n 0.5 date -u")).unwrap();
+        assert_eq!(interval, Duration::from_millis(500));
+        assert_eq!(skip_words("loop -n 0.5 date -u", consumed), "date -u");
+
+        let (interval, consumed) = parse_loop_args(&words("loop ls")).unwrap();
+        assert_eq!(interval, Duration::from_secs(2));
+        assert_eq!(skip_words("loop  ls  -la", consumed), "ls  -la");
+
+        assert!(parse_loop_args(&words("loop -n abc ls")).is_err());
+        assert!(parse_loop_args(&words("loop -n 0 ls")).is_err());
+        assert!(parse_loop_args(&words("loop -n 1")).is_err());
+    }
+
+    #[test]
+    fn variables_expand_outside_single_quotes() {
+        let lookup = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |line: &str| expand_arguments(line, &lookup);
+
+        assert_eq!(
+            expand("echo $HOME/src:$HOME"),
+            ["echo", "/home/me/src:/home/me"]
+        );
+        assert_eq!(
+            expand("echo ${HOME}x \"$HOME y\""),
+            ["echo", "/home/mex", "/home/me y"]
+        );
+        assert_eq!(
+            expand("echo '$HOME' $MISSING end"),
+            ["echo", "$HOME", "end"]
+        );
+        assert_eq!(expand("echo $ alone $"), ["echo", "$", "alone", "$"]);
+        assert_eq!(expand("echo ${unclosed $1"), ["echo", "${unclosed", "$1"]);
+        assert_eq!(expand("echo a${EMPTY}b"), ["echo", "ab"]);
+    }
+
+    #[test]
+    fn command_lists_split_outside_quotes() {
+        assert_eq!(
+            split_list("true && echo 'a && b' || echo \"c||d\" | cat").unwrap(),
+            [
+                (None, "true"),
+                (Some(Connector::And), "echo 'a && b'"),
+                (Some(Connector::Or), "echo \"c||d\" | cat"),
+            ]
+        );
+        assert_eq!(split_list("echo a\\&&b").unwrap(), [(None, "echo a\\&&b")]);
+        assert_eq!(
+            split_list("cd /tmp; echo ';' \\; ;").unwrap(),
+            [(None, "cd /tmp"), (Some(Connector::Then), "echo ';' \\;")]
+        );
+        assert!(matches!(
+            split_list("echo a;; echo b"),
+            Err(ShellError::SyntaxError(token)) if token == ";"
+        ));
+        assert!(matches!(
+            split_list("&& echo"),
+            Err(ShellError::SyntaxError(token)) if token == "&&"
+        ));
+        assert!(matches!(
+            split_list("echo ||"),
+            Err(ShellError::SyntaxError(token)) if token == "||"
+        ));
+    }
+
+    #[test]
+    fn comments_start_only_at_unquoted_word_boundaries() {
+        assert_eq!(parse_arguments("echo hi # a comment"), ["echo", "hi"]);
+        assert_eq!(
+            parse_arguments("echo foo#bar '#quoted' \\#escaped \"a #b\"#c"),
+            ["echo", "foo#bar", "#quoted", "#escaped", "a #b#c"]
+        );
+        assert_eq!(
+            split_list("echo a # b; echo c").unwrap(),
+            [(None, "echo a")]
+        );
+        assert_eq!(split_list("echo a;# b && c").unwrap(), [(None, "echo a")]);
+        assert_eq!(pending_continuation("echo hi # don't \\"), None);
+    }
+
+    #[test]
+    fn continuation_prompt_expands_like_the_main_one() {
+        let mut shell = Shell::new();
+        assert_eq!(shell.prompt_for("POPPER_UNSET_PROMPT", "> "), "> ");
+
+        shell.set_variable("PS2", "\\W\\\\ ".to_string());
+        let cwd = env::current_dir().unwrap();
+        let base = cwd.file_name().unwrap().to_string_lossy();
+        assert_eq!(shell.prompt_for("PS2", "> "), format!("{}\\ ", base));
+    }
+
+    #[test]
+    fn terminal_size_sets_columns_and_lines() {
+        let mut shell = Shell::new();
+        shell.set_terminal_size(120, 40);
+        assert_eq!(shell.lookup_variable("COLUMNS").as_deref(), Some("120"));
+        assert_eq!(shell.lookup_variable("LINES").as_deref(), Some("40"));
+
+        shell.set_terminal_size(80, 24);
+        assert_eq!(shell.lookup_variable("COLUMNS").as_deref(), Some("80"));
+        assert_eq!(shell.lookup_variable("LINES").as_deref(), Some("24"));
+    }
+
+    #[test]
+    fn redirections_are_split_from_the_command() {
+        let args = |line: &str| expand_command(line, &|_| None);
+        let words = |line: &str| parse_arguments(line);
+
+        assert_eq!(
+            parse_redirection(&args("sort -r <in.txt >> out.txt 2>err.txt")),
+            Redirections {
+                cmd_parts: words("sort -r"),
+                stdin_file: Some("in.txt".to_string()),
+                stdout_file: Some("out.txt".to_string()),
+                stdout_append: true,
+                stderr_file: Some("err.txt".to_string()),
+                ..Redirections::default()
+            }
+        );
+        assert_eq!(
+            parse_redirection(&args("make > log 2>&1")),
+            Redirections {
+                cmd_parts: words("make"),
+                stdout_file: Some("log".to_string()),
+                stderr_file: Some("log".to_string()),
+                ..Redirections::default()
+            }
+        );
+        assert_eq!(
+            parse_redirection(&args("make 2>&1 > log")),
+            Redirections {
+                cmd_parts: words("make"),
+                stdout_file: Some("log".to_string()),
+                stderr_to_stdout: true,
+                ..Redirections::default()
+            }
+        );
+        assert_eq!(
+            parse_redirection(&args("grep x <in.txt <<< 'a b' >out.txt")),
+            Redirections {
+                cmd_parts: words("grep x"),
+                stdin_data: Some("a b\n".to_string()),
+                stdout_file: Some("out.txt".to_string()),
+                ..Redirections::default()
+            }
+        );
+        // Quoted, an operator is just an argument
+        assert_eq!(
+            parse_redirection(&args("echo '<' \\>x \"2>&1\"")).cmd_parts,
+            words("echo '<' \\>x \"2>&1\"")
+        );
+    }
+
+    #[test]
+    fn ssh_hosts_are_parsed_from_known_hosts_and_config() {
+        let known_hosts = "\
+# comment
+github.com,140.82.121.4 ssh-ed25519 AAAAC3Nza
+[git.example.org]:2222 ssh-rsa AAAAB3Nza
+|1|JfKTdBh7rNbXkVAQCRp4OQoPfmI=|USECr3SWf1JUPsms5AqfD5QfxkM= ssh-rsa AAAA
+@cert-authority *.corp.example ssh-rsa AAAA
+";
+        assert_eq!(
+            parse_known_hosts(known_hosts),
+            ["github.com", "140.82.121.4", "git.example.org"]
+        );
+
+        let config = "Host build box\n  HostName 10.0.0.5\nHost *.internal !skip\nhost pi\n";
+        assert_eq!(parse_ssh_config_hosts(config), ["build", "box", "pi"]);
+    }
+
+    #[test]
+    fn only_unquoted_glob_characters_make_a_pattern() {
+        let words = split_words("*.rs '*.rs' a\\?b \"[x]\"y*", None);
+        let patterns: Vec<(&str, bool)> = words
+            .iter()
+            .map(|word| (word.pattern.as_str(), word.glob))
+            .collect();
+
+        assert_eq!(
+            patterns,
+            [
+                ("*.rs", true),
+                ("\\*.rs", false),
+                ("a\\?b", false),
+                ("\\[x]y*", true)
+            ]
+        );
+        assert_eq!(unescape_glob("\\[x]y"), "[x]y");
+    }
+
+    #[test]
+    fn leading_unquoted_tildes_expand_to_home_directories() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/home/me".to_string());
+        let expand = |line: &str| expand_arguments(line, &lookup);
+
+        assert_eq!(
+            expand("ls ~ ~/notes a~ \"~\" '~/x' \\~ ~+"),
+            [
+                "ls",
+                "/home/me",
+                "/home/me/notes",
+                "a~",
+                "~",
+                "~/x",
+                "~",
+                "~+"
+            ]
+        );
+        // root's home isn't /root everywhere, so ask the user database
+        let root = nix::unistd::User::from_name("root").unwrap().unwrap().dir;
+        assert_eq!(
+            expand("ls ~root/x ~no-such-user"),
+            ["ls", root.join("x").to_str().unwrap(), "~no-such-user"]
+        );
+    }
+
+    #[test]
+    fn logical_paths_fold_dot_dot_by_name() {
+        let join = |base: &str, path: &str| logical_join(Path::new(base), Path::new(path));
+        assert_eq!(join("/a/link", "../b/./c"), PathBuf::from("/a/b/c"));
+        assert_eq!(join("/a/link", "/x/.."), PathBuf::from("/"));
+        assert_eq!(join("/", ".."), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn fignore_hides_matching_files() {
+        let dir = scratch_dir("fignore");
+        std::fs::create_dir_all(dir.join("main.o.d")).unwrap();
+        File::create(dir.join("main.c")).unwrap();
+        File::create(dir.join("main.o")).unwrap();
+        File::create(dir.join("only.o")).unwrap();
+        let suffixes = vec![".o".to_string(), ".pyc".to_string()];
+
+        let word = format!("{}/main", dir.display());
+        let all = complete_path(&word);
+        assert_eq!(displays(&all), ["main.c", "main.o", "main.o.d/"]);
+        let kept = apply_fignore(all, &suffixes);
+        assert_eq!(displays(&kept), ["main.c", "main.o.d/"]);
+
+        // An ignored file is still offered when it is the only match
+        let word = format!("{}/only", dir.display());
+        let kept = apply_fignore(complete_path(&word), &suffixes);
+        assert_eq!(displays(&kept), ["only.o"]);
+
+        std::fs::remove_dir_all(&dir).ok();
     }
 }
@@ -0,0 +1,635 @@
+mod common;
+
+use common::{run, run_in, run_with, stderr, stdout, TempDir};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+#[test]
+fn builtin_forces_the_builtin_command() {
+    let output = run("builtin echo hello\nbuiltin type cd\nbuiltin pwd | cat\n");
+    let text = stdout(&output);
+
+    assert!(text.starts_with("hello\ncd is a shell builtin\n"));
+    assert_eq!(text.lines().count(), 3);
+}
+
+#[test]
+fn true_and_false_are_builtins_that_set_the_status() {
+    let output = run_with(
+        "true && echo yes\nfalse || echo no\nfalse | true; echo $?\ntrue | false; echo $?\n\
+         type true false\n",
+        |command| {
+            command.env("PATH", "");
+        },
+    );
+
+    assert_eq!(
+        stdout(&output),
+        "yes\nno\n0\n1\ntrue is a shell builtin\nfalse is a shell builtin\n"
+    );
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn builtin_rejects_non_builtins() {
+    let output = run("builtin cat\n");
+
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("builtin: cat: not a shell builtin"));
+}
+
+#[test]
+fn kill_l_translates_signal_numbers_and_names() {
+    let output = run("kill -l 9\nkill -l TERM\nkill -l 137\n");
+
+    assert_eq!(stdout(&output), "KILL\n15\nKILL\n");
+}
+
+#[test]
+fn kill_l_lists_signals() {
+    let text = stdout(&run("kill -l\n"));
+
+    assert!(text.contains(" 9) SIGKILL"));
+    assert!(text.contains("15) SIGTERM"));
+}
+
+#[test]
+fn kill_sends_signals_to_processes() {
+    let mut child = std::process::Command::new("sleep")
+        .arg("30")
+        .spawn()
+        .unwrap();
+    run(&format!("kill -s KILL {}\n", child.id()));
+
+    let status = child.wait().unwrap();
+    assert_eq!(status.signal(), Some(9));
+}
+
+#[test]
+fn kill_failures_set_a_nonzero_status() {
+    let script = "kill 999999 2>/dev/null; echo $?\n\
+                  kill -BOGUS 1 2>/dev/null; echo $?\n\
+                  kill 2>/dev/null; echo $?\n\
+                  kill -l 9 | kill -l NOPE; echo $?\n\
+                  kill -l 9; echo $?\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "1\n1\n1\n1\nKILL\n0\n");
+}
+
+#[test]
+fn echo_z_separates_arguments_with_nul() {
+    let output = run("echo -z a b c\necho -z one two | cat\n");
+
+    assert_eq!(output.stdout, b"a\0b\0c\0one\0two\0");
+}
+
+#[test]
+fn cd_searches_cdpath_for_relative_names() {
+    let tmp = TempDir::new("cdpath");
+    std::fs::create_dir_all(tmp.path().join("projects/popper")).unwrap();
+
+    let output = run_with("cd popper\npwd\n", |command| {
+        command.env("CDPATH", tmp.path().join("projects"));
+    });
+    let expected = tmp.path().join("projects/popper");
+
+    assert_eq!(
+        stdout(&output),
+        format!("{}\n{}\n", expected.display(), expected.display())
+    );
+}
+
+#[test]
+fn cd_takes_its_directory_from_a_command_substitution() {
+    let tmp = TempDir::new("cd-substitution");
+    std::fs::create_dir(tmp.path().join("my dir")).unwrap();
+    std::fs::write(tmp.path().join("dir.txt"), "my dir\n").unwrap();
+    let root = tmp.path().canonicalize().unwrap();
+    let output = run_in(&root, "cd \"$(cat dir.txt)\"; pwd\ncd $(echo /tmp); pwd\n");
+
+    assert_eq!(
+        stdout(&output),
+        format!("{}/my dir\n/tmp\n", root.display())
+    );
+}
+
+#[test]
+fn cd_double_dash_ends_option_parsing() {
+    let tmp = TempDir::new("cd-dashdash");
+    std::fs::create_dir(tmp.path().join("-P")).unwrap();
+    let output = run_in(tmp.path(), "cd -- -P\npwd\ncd -P ..\npwd\ncd -x\n");
+    let root = tmp.path().canonicalize().unwrap();
+
+    assert_eq!(
+        stdout(&output),
+        format!("{}/-P\n{}\n", root.display(), root.display())
+    );
+    assert_eq!(stderr(&output), "cd: -x: invalid option\n");
+}
+
+#[test]
+fn logical_pwd_follows_cd_through_symlinks() {
+    let tmp = TempDir::new("cd-logical");
+    let root = tmp.path().canonicalize().unwrap();
+    std::fs::create_dir_all(root.join("real/sub")).unwrap();
+    std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+    let script = "cd link/sub
+pwd
+echo $PWD
+pwd -P
+pwd | cat
+\
+                  cd ..
+pwd
+cd -P sub
+pwd
+echo $PWD
+";
+    let output = run_in(&root, script);
+
+    let (link, real) = (root.join("link"), root.join("real"));
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{0}/sub\n{0}/sub\n{1}/sub\n{0}/sub\n{0}\n{1}/sub\n{1}/sub\n",
+            link.display(),
+            real.display()
+        )
+    );
+}
+
+#[test]
+fn repeat_runs_a_command_n_times() {
+    let output = run("repeat 3 echo hi\nrepeat 2 echo piped | cat\n");
+    assert_eq!(stdout(&output), "hi\nhi\nhi\npiped\npiped\n");
+
+    let tmp = TempDir::new("repeat");
+    let log = tmp.path().join("log");
+    let output = run(&format!(
+        "repeat 3 sh -c 'echo x >> {}'\nrepeat 2 true\n",
+        log.display()
+    ));
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "x\nx\nx\n");
+}
+
+#[test]
+fn repeat_keeps_going_after_a_failing_run() {
+    let script = "repeat 3 nope\necho $?\nfalse\nrepeat 3 sh -c 'echo $1; exit 3' - $?\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "127\n1\n3\n3\n");
+    assert_eq!(stderr(&output), "nope: command not found\n".repeat(3));
+}
+
+#[test]
+fn repeat_rejects_a_non_numeric_count() {
+    let output = run("repeat many echo hi\n");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "repeat: many: invalid count\n");
+}
+
+#[test]
+fn loop_reruns_until_interrupted_then_returns_to_the_prompt() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_popper"))
+        .env("HISTFILE", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"loop -n 0.1 echo tick\necho after\n")
+        .unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let text = stdout(&output);
+
+    assert!(text.matches("tick\n").count() >= 2, "{:?}", text);
+    assert!(text.contains("\x1b[H\x1b[2J"));
+    assert!(text.ends_with("after\n"));
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn exit_status_wraps_into_a_byte() {
+    assert_eq!(run("exit 300\n").status.code(), Some(44));
+    assert_eq!(run("exit -1\n").status.code(), Some(255));
+    assert_eq!(run("exit 7\necho unreachable\n").status.code(), Some(7));
+}
+
+#[test]
+fn exit_defaults_to_the_last_status() {
+    assert_eq!(run("sh -c 'exit 5'\nexit\n").status.code(), Some(5));
+}
+
+#[test]
+fn exit_rejects_non_numeric_status() {
+    let output = run("exit abc\n");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stderr(&output), "exit: abc: numeric argument required\n");
+}
+
+#[test]
+fn compgen_filters_a_word_list() {
+    let output = run("compgen -W \"foo bar baz\" -- b\ncompgen -W \"foo bar\" -- z\necho $?\n");
+
+    assert_eq!(stdout(&output), "bar\nbaz\n1\n");
+}
+
+#[test]
+fn compgen_generates_commands_and_files() {
+    let tmp = TempDir::new("compgen");
+    std::fs::create_dir(tmp.path().join("notes.d")).unwrap();
+    std::fs::write(tmp.path().join("notes.txt"), "").unwrap();
+    let output = run_with("compgen -c comp\ncompgen -f note\n", |cmd| {
+        cmd.current_dir(tmp.path()).env("PATH", tmp.path());
+    });
+
+    assert_eq!(stdout(&output), "compgen\nnotes.d\nnotes.txt\n");
+}
+
+#[test]
+fn aliases_expand_the_command_word_once() {
+    let script = "alias greet='echo hello' say=\"echo it's\"\n\
+                  greet world\n\
+                  alias echo='echo [aliased]'\n\
+                  echo once\n\
+                  alias\n\
+                  unalias echo greet\n\
+                  greet\n\
+                  alias say nope\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "hello world\n[aliased] once\n\
+         alias echo='echo [aliased]'\nalias greet='echo hello'\nalias say='echo it'\\''s'\n\
+         alias say='echo it'\\''s'\n"
+    );
+    assert_eq!(
+        stderr(&output),
+        "greet: command not found\nalias: nope: not found\n"
+    );
+}
+
+#[test]
+fn alias_values_ending_in_a_blank_expand_the_next_word() {
+    let script = "alias s='echo ' hi='echo HI' e='env '\n\
+                  s hi\ne hi\necho hi | e hi\n\
+                  alias e2='e '\ne2 e hi\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "echo HI\nHI\nHI\nHI\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn aliases_expand_only_in_command_position_and_never_recurse() {
+    let script = "alias hi='echo hi' sh=\"sh -c 'echo $0'\" a=b b=a\n\
+                  echo hi\nhi there\nsh\na\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "hi\nhi there\nsh\n");
+    assert_eq!(stderr(&output), "a: command not found\n");
+}
+
+#[test]
+fn login_shells_source_the_profile() {
+    let home = TempDir::new("login-home");
+    std::fs::write(
+        home.path().join(".popper_profile"),
+        "# set up the session\nexport GREETING=hi\necho from \\\n  profile\n",
+    )
+    .unwrap();
+
+    let login = run_with("echo $GREETING\n", |command| {
+        command.arg("--login").env("HOME", home.path());
+    });
+    let plain = run_with("echo $GREETING\n", |command| {
+        command.env("HOME", home.path()).env_remove("GREETING");
+    });
+
+    assert!(
+        stdout(&login).ends_with("from profile\nhi\n"),
+        "{}",
+        stdout(&login)
+    );
+    assert_eq!(stdout(&plain), "\n");
+}
+
+#[test]
+fn cd_dash_returns_to_the_previous_directory() {
+    let tmp = TempDir::new("cd-dash");
+    std::fs::create_dir(tmp.path().join("sub")).unwrap();
+    let root = tmp.path().canonicalize().unwrap();
+    let script = "cd -\ncd sub\nsh -c 'echo $OLDPWD $PWD'\ncd -\npwd\ncd -\n";
+    let output = run_in(&root, script);
+
+    let sub = root.join("sub");
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "{root} {sub}\n{root}\n{root}\n{sub}\n",
+            root = root.display(),
+            sub = sub.display()
+        )
+    );
+    assert_eq!(stderr(&output), "cd: OLDPWD not set\n");
+}
+
+#[test]
+fn bare_cd_goes_home() {
+    let home = TempDir::new("cd-home");
+    let home_path = home.path().canonicalize().unwrap();
+    let output = run_with("cd\npwd\n", |command| {
+        command.env("HOME", &home_path);
+    });
+    let unset = run_with("cd\n", |command| {
+        command.env_remove("HOME");
+    });
+
+    assert_eq!(stdout(&output), format!("{}\n", home_path.display()));
+    assert_eq!(stderr(&unset), "cd: HOME not set\n");
+    assert_eq!(unset.status.code(), Some(1));
+}
+
+#[test]
+fn source_runs_a_script_in_the_current_shell() {
+    let tmp = TempDir::new("source");
+    std::fs::create_dir(tmp.path().join("sub")).unwrap();
+    std::fs::write(
+        tmp.path().join("setup.sh"),
+        "# configure the session\n\ncd sub\nexport FROM_SCRIPT=yes\nalias hi='echo hi'\n",
+    )
+    .unwrap();
+    let root = tmp.path().canonicalize().unwrap();
+    let output = run_in(
+        &root,
+        "source setup.sh\npwd\necho $FROM_SCRIPT\nhi\n. ../missing.sh\necho $?\n",
+    );
+
+    assert_eq!(
+        stdout(&output),
+        format!("{}/sub\nyes\nhi\n1\n", root.display())
+    );
+    assert_eq!(
+        stderr(&output),
+        "popper: .: ../missing.sh: No such file or directory\n"
+    );
+}
+
+#[test]
+fn return_ends_a_sourced_script_with_a_status() {
+    let tmp = TempDir::new("return");
+    std::fs::write(
+        tmp.path().join("early.sh"),
+        "echo before\ntrue && return 3\necho after\n",
+    )
+    .unwrap();
+    std::fs::write(
+        tmp.path().join("outer.sh"),
+        "source early.sh\necho inner $?\nfalse\nreturn\necho unreachable\n",
+    )
+    .unwrap();
+    let output = run_in(
+        tmp.path(),
+        "source outer.sh\necho outer $?\nreturn 1\necho $?\n\
+         echo 'return x' > bad.sh\nsource bad.sh\necho $?\n",
+    );
+
+    assert_eq!(stdout(&output), "before\ninner 3\nouter 1\n1\n2\n");
+    assert_eq!(
+        stderr(&output),
+        "return: can only `return' from a sourced script\n\
+         return: x: numeric argument required\n"
+    );
+}
+
+#[test]
+fn sigint_interrupts_the_foreground_command_not_the_shell() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_popper"))
+        .env("HISTFILE", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"sleep 30\necho status $?\n")
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(started.elapsed().as_secs() < 10);
+    assert_eq!(stdout(&output), "status 130\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn sigint_interrupts_a_builtin_reading_the_shells_stdin() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_popper"))
+        .env("HISTFILE", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"wc -l\n").unwrap();
+
+    // wc is now waiting for input that never ends
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    stdin.write_all(b"echo status $?\n").unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(stdout(&output), "status 130\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn grep_builtin_flags() {
+    let tmp = TempDir::new("grep");
+    std::fs::write(tmp.path().join("a.txt"), "Foo bar\nbaz\nfood\n").unwrap();
+    std::fs::write(tmp.path().join("b.txt"), "nothing\nfoo.\n").unwrap();
+    let script = "grep 'fo+d' a.txt\n\
+                  grep -i foo a.txt\n\
+                  grep -v foo a.txt\n\
+                  grep -n ba a.txt\n\
+                  grep -c o a.txt\n\
+                  grep -F 'foo.' a.txt b.txt\n\
+                  grep -ic foo a.txt b.txt\n\
+                  echo one two | grep -q two && echo quiet\n\
+                  grep zzz a.txt; echo $?\n\
+                  grep foo missing.txt; echo $?\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "food\n\
+         Foo bar\nfood\n\
+         Foo bar\nbaz\n\
+         1:Foo bar\n2:baz\n\
+         2\n\
+         b.txt:foo.\n\
+         a.txt:2\nb.txt:1\n\
+         quiet\n\
+         1\n\
+         2\n"
+    );
+    assert_eq!(
+        stderr(&output),
+        "grep: missing.txt: No such file or directory\n"
+    );
+}
+
+#[test]
+fn wc_builtin_counts_stdin_and_files() {
+    let tmp = TempDir::new("wc");
+    std::fs::write(tmp.path().join("short"), "a b\nc\n").unwrap();
+    std::fs::write(tmp.path().join("long"), "hello world foo\n".repeat(80)).unwrap();
+    let script = "echo a b c | wc -w\n\
+                  echo hi | wc\n\
+                  echo hi | wc -lw\n\
+                  wc -l short\n\
+                  wc short\n\
+                  wc short long\n\
+                  wc -c short missing; echo $?\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "3\n\
+         \x20     1       1       3\n\
+         \x20     1       1\n\
+         2 short\n\
+         2 3 6 short\n\
+         \x20  2    3    6 short\n  80  240 1280 long\n  82  243 1286 total\n\
+         6 short\n6 total\n1\n"
+    );
+    assert_eq!(stderr(&output), "wc: missing: No such file or directory\n");
+}
+
+#[test]
+fn restricted_mode_blocks_cd_paths_and_output_redirection() {
+    let tmp = TempDir::new("restricted");
+    let root = tmp.path().canonicalize().unwrap();
+    let script = "cd /\n/bin/ls\nexport PATH=/tmp\nenv SHELL=/bin/sh ls\necho hi > out.txt\n\
+                  echo hi | cat 2>&1\n\
+                  pwd\necho $?\n";
+    let output = run_with(script, |command| {
+        command.current_dir(&root).arg("-r");
+    });
+
+    assert_eq!(stdout(&output), format!("{}\n0\n", root.display()));
+    assert_eq!(
+        stderr(&output),
+        "restricted: cannot change directory\n\
+         restricted: cannot specify `/' in command names\n\
+         restricted: cannot set PATH\n\
+         restricted: cannot set SHELL\n\
+         restricted: cannot redirect output\n\
+         restricted: cannot redirect output\n"
+    );
+    assert!(!root.join("out.txt").exists());
+
+    let output = run_with("cd /\n", |command| {
+        command.arg0("rpopper");
+    });
+    assert_eq!(stderr(&output), "restricted: cannot change directory\n");
+}
+
+#[test]
+fn test_and_brackets_report_through_the_status() {
+    let tmp = TempDir::new("test-builtin");
+    std::fs::create_dir(tmp.path().join("dir")).unwrap();
+    std::fs::write(tmp.path().join("file"), "").unwrap();
+    let script = "test -f file && echo f\n[ -d dir ] && echo d\n[ -e nope ] || echo missing\n\
+                  [ a b c d ]; echo $?\n\
+                  test abc = abc && test abc != abd && echo strings\n\
+                  [ -z '' ] && [ -n x ] && [ x ] && test '' || echo empty\n\
+                  [ 3 -lt 10 ] && [ 10 -ge 10 ] && [ ! 2 -eq 3 ]; echo $?\n\
+                  [ ! -x file ] && echo not-executable\n\
+                  test; echo $?\n\
+                  [ 1 -eq 1; echo $?\n\
+                  test a -lt 2; echo $?\n\
+                  test -q x; echo $?\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "f\nd\nmissing\n2\nstrings\nempty\n0\nnot-executable\n1\n2\n2\n2\n"
+    );
+    assert_eq!(
+        stderr(&output),
+        "[: too many arguments\n[: missing `]'\ntest: a: integer expression expected\n\
+         test: -q: unary operator expected\n"
+    );
+}
+
+#[cfg(feature = "ls")]
+#[test]
+fn ls_lists_names_one_per_line_and_shows_hidden_ones_with_a() {
+    let tmp = TempDir::new("ls-builtin");
+    for name in ["b.txt", "a.txt", ".hidden", "sub/inner"] {
+        let path = tmp.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    let script = "type ls\nls -1\necho --\nls -a\necho --\nls -1r sub a.txt\n\
+                  ls missing; echo $?\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "ls is a shell builtin\na.txt\nb.txt\nsub\n--\n\
+         .\n..\n.hidden\na.txt\nb.txt\nsub\n--\n\
+         a.txt\n\nsub:\ninner\n2\n"
+    );
+    assert_eq!(
+        stderr(&output),
+        "ls: cannot access 'missing': No such file or directory\n"
+    );
+}
+
+#[cfg(not(feature = "ls"))]
+#[test]
+fn ls_is_not_a_builtin_unless_the_feature_is_enabled() {
+    let output = run("type ls\n");
+
+    assert_ne!(stdout(&output), "ls is a shell builtin\n");
+}
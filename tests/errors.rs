@@ -0,0 +1,55 @@
+mod common;
+
+use common::{run, stderr, stdout};
+
+#[test]
+fn command_not_found_exits_127() {
+    let output = run("definitely-not-a-command --flag\n");
+
+    assert_eq!(output.status.code(), Some(127));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "definitely-not-a-command: command not found\n"
+    );
+}
+
+#[test]
+fn failed_redirection_exits_1() {
+    let output = run("echo hi > /nonexistent-dir/out.txt\n");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "/nonexistent-dir/out.txt: No such file or directory\n"
+    );
+}
+
+#[test]
+fn syntax_errors_exit_2() {
+    let output = run("| cat\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("syntax error near unexpected token `|'"));
+
+    let output = run("echo hi >\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("`newline'"));
+}
+
+#[test]
+fn builtin_failures_exit_1() {
+    let output = run("cd /nonexistent-dir\n");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "cd: /nonexistent-dir: No such file or directory\n"
+    );
+}
+
+#[test]
+fn status_of_last_command_is_the_exit_status() {
+    assert_eq!(run("sh -c 'exit 3'\n").status.code(), Some(3));
+    assert_eq!(run("nope\necho recovered\n").status.code(), Some(0));
+    assert_eq!(run("true | sh -c 'exit 4'\n").status.code(), Some(4));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{run, run_with, stderr, stdout, TempDir};
+
+#[test]
+fn histignore_patterns_are_not_recorded() {
+    let output = run_with(
+        "echo --password=hunter2\necho visible\nhistory\n",
+        |command| {
+            command.env("HISTIGNORE", "*--password*:ls");
+        },
+    );
+    let text = stdout(&output);
+
+    assert!(!text.contains("echo --password"));
+    assert!(text.contains("    1  echo visible"));
+    assert!(text.contains("    2  history"));
+}
+
+#[test]
+fn histcontrol_ignorespace_skips_space_prefixed_lines() {
+    let output = run_with(" echo secret\necho shown\nhistory\n", |command| {
+        command.env("HISTCONTROL", "ignorespace");
+    });
+    let text = stdout(&output);
+
+    assert!(text.contains("    1  echo shown"));
+    assert!(!text.contains("echo secret"));
+}
+
+#[test]
+fn word_designators_reuse_previous_arguments() {
+    let output = run("echo first /tmp\necho !^ !$\n");
+
+    assert_eq!(stdout(&output), "first /tmp\necho first /tmp\nfirst /tmp\n");
+}
+
+#[test]
+fn word_designators_keep_a_quoted_argument_as_one_word() {
+    let output = run("echo \"my  dir\"\nprintf '[%s]\\n' !$\n");
+
+    assert_eq!(
+        stdout(&output),
+        "my  dir\nprintf '[%s]\\n' \"my  dir\"\n[my  dir]\n"
+    );
+}
+
+#[test]
+fn bang_bang_reruns_the_previous_command() {
+    let output = run("echo again\n!!\nhistory\n");
+
+    assert_eq!(
+        stdout(&output),
+        "again\necho again\nagain\n    1  echo again\n    2  echo again\n    3  history\n"
+    );
+}
+
+#[test]
+fn bang_bang_without_history_reports_no_event() {
+    let output = run("!!\n");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "!!: event not found\n");
+}
+
+#[test]
+fn history_persists_in_the_home_directory_by_default() {
+    let home = TempDir::new("history-home");
+    let session = |script: &str| {
+        run_with(script, |command| {
+            command.env_remove("HISTFILE").env("HOME", home.path());
+        })
+    };
+
+    session("echo first\n");
+    assert_eq!(
+        std::fs::read_to_string(home.path().join(".popper_history")).unwrap(),
+        "echo first\n"
+    );
+
+    let output = session("history\n");
+    assert_eq!(stdout(&output), "    1  echo first\n    2  history\n");
+}
+
+#[test]
+fn history_lists_numbered_entries_and_limits_to_the_last_n() {
+    let output = run("echo one\necho two\nhistory 2\nhistory\n");
+
+    assert_eq!(
+        stdout(&output),
+        "one\ntwo\n    2  echo two\n    3  history 2\n    1  echo one\n    2  echo two\n    3  history 2\n    4  history\n"
+    );
+}
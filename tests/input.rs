@@ -0,0 +1,73 @@
+mod common;
+
+use common::{run, run_in, run_with, stderr, stdout, TempDir};
+
+#[test]
+fn open_quotes_continue_onto_the_next_line() {
+    let output = run("echo \"first\nsecond\"\necho 'a\nb'\n");
+
+    assert_eq!(stdout(&output), "first\nsecond\na\nb\n");
+}
+
+#[test]
+fn trailing_backslash_joins_lines() {
+    let output = run("echo one \\\ntwo\n");
+
+    assert_eq!(stdout(&output), "one two\n");
+}
+
+#[test]
+fn eof_inside_quotes_is_a_syntax_error() {
+    let output = run("echo \"never closed\n");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "unexpected EOF while looking for matching `\"'\n"
+    );
+}
+
+#[test]
+fn here_documents_feed_the_following_lines_to_stdin() {
+    let script = "export -n NAME=world\n\
+                  cat <<EOF | tr a-z A-Z\nhello $NAME\nEOF\n\
+                  cat <<-'END'\n\t$NAME stays\n\tEND\n\
+                  false && cat <<A; wc -l << B\nskipped\nA\none\ntwo\nB\n\
+                  cat <<EOF\nno end\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "HELLO WORLD\n$NAME stays\n2\nno end\n");
+    assert_eq!(
+        stderr(&output),
+        "popper: warning: here-document delimited by end-of-file (wanted `EOF')\n"
+    );
+}
+
+#[test]
+fn here_strings_feed_a_word_and_a_newline_to_stdin() {
+    let tmp = TempDir::new("here-string");
+    let script = "export -n LINE='a foo b'\n\
+                  grep foo <<< \"$LINE\"\n\
+                  wc -w <<<\"$LINE\" > count.txt\ncat count.txt\n\
+                  cat <<< \"$(echo sub)\" | tr a-z A-Z\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(stdout(&output), "a foo b\n3\nSUB\n");
+}
+
+#[test]
+fn quoting_the_delimiter_keeps_a_here_document_literal() {
+    let script = "cat <<EOF\n$HOME ${HOME} $(echo sub) \\$HOME\nEOF\n\
+                  cat <<'EOF'\n$HOME ${HOME} $(echo sub) \\$HOME\nEOF\n\
+                  cat <<\"EOF\"\n$HOME\nEOF\n";
+    let output = run_with(script, |cmd| {
+        cmd.env("HOME", "/home/tester");
+    });
+
+    assert_eq!(
+        stdout(&output),
+        "/home/tester /home/tester sub $HOME\n\
+         $HOME ${HOME} $(echo sub) \\$HOME\n\
+         $HOME\n"
+    );
+}
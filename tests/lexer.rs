@@ -0,0 +1,18 @@
+use popper::lexer::{tokenize, words, TokenKind};
+
+#[test]
+fn other_tools_can_split_a_line_like_the_shell() {
+    let line = "grep 'a|b' notes.txt|wc -l >count";
+    let tokens = tokenize(line).unwrap();
+
+    let operators: Vec<&str> = tokens
+        .iter()
+        .filter(|token| token.kind == TokenKind::Operator)
+        .map(|token| &line[token.span.clone()])
+        .collect();
+    assert_eq!(operators, ["|", ">"]);
+    assert_eq!(
+        words(line).unwrap(),
+        ["grep", "'a|b'", "notes.txt", "|", "wc", "-l", ">", "count"]
+    );
+}
@@ -0,0 +1,220 @@
+mod common;
+
+use common::{run, run_in, run_with, stderr, stdout, TempDir};
+
+#[test]
+fn builtin_and_external_output_keep_their_order() {
+    let output = run("echo one\nsh -c 'printf two'\necho three\npwd | cat\necho four\n");
+    let text = stdout(&output);
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert_eq!(lines[0], "one");
+    assert_eq!(lines[1], "twothree");
+    assert_eq!(lines[3], "four");
+}
+
+#[test]
+fn large_builtin_output_flows_through_a_pipeline() {
+    let script = format!("echo {} | cat | wc -c\n", "a".repeat(200_000));
+    let output = run(&script);
+
+    assert_eq!(stdout(&output).trim(), "200001");
+}
+
+#[test]
+fn stderr_merged_into_a_pipe_reaches_the_next_stage() {
+    let output = run("sh -c 'echo oops >&2; echo fine' 2>&1 | grep oops\n");
+    assert_eq!(stdout(&output), "oops\n");
+    assert!(output.stderr.is_empty());
+
+    let output = run("echo hi | sh -c 'cat; echo late >&2' 2>&1\n");
+    assert_eq!(stdout(&output), "hi\nlate\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn stderr_can_be_silenced_with_dev_null() {
+    let output = run("sh -c 'echo out; echo err >&2' 2>/dev/null\necho quiet > /dev/null\n");
+
+    assert_eq!(stdout(&output), "out\n");
+    assert!(output.stderr.is_empty());
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn every_dev_null_redirection_discards_the_stream() {
+    let script = "sh -c 'echo out; echo err >&2' > /dev/null 2>&1\n\
+                  sh -c 'echo out; echo err >&2' >>/dev/null 2>>/dev/null\n\
+                  sh -c 'echo out; echo err >&2' 2>/dev/null >/dev/null\n\
+                  echo $?\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "0\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn closed_descriptors_discard_output() {
+    let output = run("sh -c 'echo out; echo err >&2' 2>&-\nsh -c 'echo gone' >&-\n");
+
+    assert_eq!(stdout(&output), "out\n");
+    // sh may complain about the failed write on its still-open stderr,
+    // but the discarded line itself never shows up
+    assert!(!stderr(&output).lines().any(|line| line == "err"));
+}
+
+#[test]
+fn builtin_filters_honor_stderr_redirections() {
+    let tmp = TempDir::new("filter-stderr");
+    let script = "grep foo missing 2>/dev/null; echo $?\n\
+                  wc -l missing 2>err.txt\ngrep foo missing 2>>err.txt\n\
+                  echo \"[$(grep foo missing 2>&1)]\"\n\
+                  grep foo <<< food 1>&2\n\
+                  wc -l missing >&-\ngrep foo missing 2>&-\n\
+                  cat err.txt\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "2\n\
+         [grep: missing: No such file or directory]\n\
+         wc: missing: No such file or directory\n\
+         grep: missing: No such file or directory\n"
+    );
+    assert_eq!(
+        stderr(&output),
+        "food\nwc: missing: No such file or directory\n"
+    );
+    assert!(!tmp.path().join("&-").exists());
+}
+
+#[test]
+fn glued_redirection_accepts_a_quoted_target_with_spaces() {
+    let tmp = TempDir::new("quoted-target");
+    let output = run_in(
+        tmp.path(),
+        "echo hi >\"my out.txt\"\nsh -c 'echo err >&2' 2>'my err.txt'\necho more >>\"my out.txt\"\n",
+    );
+
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("my out.txt")).unwrap(),
+        "hi\nmore\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("my err.txt")).unwrap(),
+        "err\n"
+    );
+}
+
+#[test]
+fn quoted_greater_than_signs_create_no_files() {
+    let tmp = TempDir::new("quoted-greater");
+    let output = run_in(tmp.path(), "echo '>' y\necho \">x\"\necho a\\>b\n");
+
+    assert_eq!(stdout(&output), "> y\n>x\na>b\n");
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn input_redirection_feeds_a_file_to_stdin() {
+    let tmp = TempDir::new("input-redirect");
+    std::fs::write(tmp.path().join("input.txt"), "pear\napple\n").unwrap();
+    let output = run_in(
+        tmp.path(),
+        "sort < input.txt\nwc -l <input.txt > count.txt\ncat count.txt\n",
+    );
+
+    assert_eq!(stdout(&output), "apple\npear\n2\n");
+}
+
+#[test]
+fn missing_input_file_skips_the_command() {
+    let tmp = TempDir::new("input-missing");
+    let output = run_in(tmp.path(), "cat < nope.txt > out.txt\necho $?\n");
+
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(stderr(&output), "nope.txt: No such file or directory\n");
+    assert!(!tmp.path().join("out.txt").exists());
+}
+
+#[test]
+fn quoted_less_than_signs_are_arguments() {
+    let output = run("echo \"<b>\"\necho '<'\necho x\\<y\n");
+
+    assert_eq!(stdout(&output), "<b>\n<\nx<y\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn duplicated_streams_follow_redirection_order() {
+    let tmp = TempDir::new("dup-order");
+    let script = "sh -c 'echo out; echo err >&2' > both.txt 2>&1\n\
+                  sh -c 'echo out; echo err >&2' 2>&1 > only-out.txt\n\
+                  sh -c 'echo out; echo err >&2' 2>&1\n\
+                  echo to-stderr 1>&2\n\
+                  sh -c 'echo moved' >&2 2> moved.txt\n";
+    let output = run_in(tmp.path(), script);
+
+    let read = |name: &str| std::fs::read_to_string(tmp.path().join(name)).unwrap();
+    assert_eq!(read("both.txt"), "out\nerr\n");
+    assert_eq!(read("only-out.txt"), "out\n");
+    assert_eq!(read("moved.txt"), "");
+    assert_eq!(stdout(&output), "err\nout\nerr\n");
+    assert_eq!(stderr(&output), "to-stderr\nmoved\n");
+    assert!(!tmp.path().join("&1").exists());
+}
+
+#[test]
+fn quoted_stream_duplication_is_an_argument() {
+    let output = run("echo \"2>&1\" foo\nsh -c 'echo \"$1\"' - '1>&2'\n");
+
+    assert_eq!(stdout(&output), "2>&1 foo\n1>&2\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn reporttime_reports_slow_commands() {
+    let output = run("export REPORTTIME=0.1\nsleep 0.3\necho fast\n");
+
+    assert_eq!(stdout(&output), "fast\n");
+    let report = stderr(&output);
+    assert_eq!(report.lines().count(), 1, "{}", report);
+    assert!(report.starts_with("sleep 0.3  "), "{}", report);
+    assert!(report.contains("s user ") && report.trim_end().ends_with(" total"));
+}
+
+#[test]
+fn trace_file_logs_each_command_as_json() {
+    let tmp = TempDir::new("trace");
+    let log = tmp.path().join("trace.jsonl");
+    let root = tmp.path().canonicalize().unwrap();
+    let output = run_with("echo 'a b' c; sh -c 'exit 3'\n", |command| {
+        command.current_dir(&root).env("POPPER_TRACE_FILE", &log);
+    });
+    assert_eq!(stdout(&output), "a b c\n");
+
+    let text = std::fs::read_to_string(&log).unwrap();
+    let entries: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries.len(), 2, "{}", text);
+    assert_eq!(entries[0]["command"], "echo");
+    assert_eq!(entries[0]["args"], serde_json::json!(["a b", "c"]));
+    assert_eq!(entries[0]["cwd"], root.display().to_string());
+    assert_eq!(entries[0]["status"], 0);
+    assert!(entries[0]["duration_ms"].as_f64().unwrap() >= 0.0);
+    assert_eq!(entries[1]["command"], "sh");
+    assert_eq!(entries[1]["status"], 3);
+}
+
+#[test]
+fn unwritable_trace_file_does_not_break_commands() {
+    let output = run_with("echo still\n", |command| {
+        command.env("POPPER_TRACE_FILE", "/nonexistent/dir/trace");
+    });
+
+    assert_eq!(stdout(&output), "still\n");
+    assert_eq!(stderr(&output), "");
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{run_with, stdout, TempDir};
+
+#[test]
+fn commands_resolve_without_path() {
+    let output = run_with("type sh\nsh -c 'echo ran'\n", |command| {
+        command.env_remove("PATH");
+    });
+    let text = stdout(&output);
+
+    assert!(text.contains("sh is /usr/bin/sh") || text.contains("sh is /bin/sh"));
+    assert!(text.contains("ran\n"));
+}
+
+#[test]
+fn directories_in_path_are_not_commands() {
+    let tmp = TempDir::new("path-dir");
+    std::fs::create_dir(tmp.path().join("sh")).unwrap();
+    std::fs::create_dir(tmp.path().join("only-a-dir")).unwrap();
+    let path = format!("{}:/usr/bin:/bin", tmp.path().display());
+    let output = run_with("type sh only-a-dir\nsh -c 'echo ran'\n", |command| {
+        command.env("PATH", &path);
+    });
+    let text = stdout(&output);
+
+    assert!(text.contains("sh is /usr/bin/sh") || text.contains("sh is /bin/sh"));
+    assert!(text.contains("only-a-dir: not found\n"));
+    assert!(text.ends_with("ran\n"));
+}
+
+#[test]
+fn which_prints_the_path_of_each_command_found() {
+    let tmp = TempDir::new("which");
+    std::fs::create_dir(tmp.path().join("only-a-dir")).unwrap();
+    let path = format!("{}:/usr/bin:/bin", tmp.path().display());
+    let script = "which sh\necho $?\nwhich only-a-dir nope-not-a-command\necho $?\n\
+                  which sh sh | wc -l\n";
+    let output = run_with(script, |command| {
+        command.env("PATH", &path);
+    });
+    let text = stdout(&output);
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert!(
+        lines[0] == "/usr/bin/sh" || lines[0] == "/bin/sh",
+        "{}",
+        text
+    );
+    assert_eq!(lines[1..], ["0", "1", "2"]);
+}
+
+#[test]
+fn unquoted_globs_expand_to_sorted_matches() {
+    let tmp = TempDir::new("glob");
+    std::fs::create_dir(tmp.path().join("sub")).unwrap();
+    for name in ["b.rs", "a.rs", "c.txt", ".hidden.rs", "sub/x.rs"] {
+        std::fs::write(tmp.path().join(name), "").unwrap();
+    }
+    let script = "echo *.rs\necho \"*.rs\" '*.rs' \\*.rs\necho */?.rs [ab].rs\necho none*\n";
+    let output = run_with(script, |command| {
+        command.current_dir(tmp.path());
+    });
+
+    assert_eq!(
+        stdout(&output),
+        "a.rs b.rs\n*.rs *.rs *.rs\nsub/x.rs a.rs b.rs\nnone*\n"
+    );
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use common::{run, run_in, stderr, stdout, TempDir};
+
+#[test]
+fn three_stage_pipeline_connects_every_stage() {
+    let output = run("printf 'foo\\nbar\\nfood\\n' | grep foo | wc -l\n");
+
+    assert_eq!(stdout(&output).trim(), "2");
+}
+
+#[test]
+fn builtins_work_in_the_middle_of_a_chain() {
+    let output = run("printf 'ignored\\n' | echo middle | tr a-z A-Z\n");
+
+    assert_eq!(stdout(&output), "MIDDLE\n");
+}
+
+#[test]
+fn pipeline_status_is_the_last_stage() {
+    assert_eq!(
+        run("false | true | sh -c 'exit 6'\n").status.code(),
+        Some(6)
+    );
+    assert_eq!(run("sh -c 'exit 6' | cat | true\n").status.code(), Some(0));
+}
+
+#[test]
+fn and_or_lists_short_circuit_on_status() {
+    let output = run(
+        "false && echo skipped\ntrue && echo ran\nfalse || echo fallback\ntrue || echo skipped\n",
+    );
+
+    assert_eq!(stdout(&output), "ran\nfallback\n");
+}
+
+#[test]
+fn and_or_operands_can_be_pipelines() {
+    let output = run("echo abc | grep -q x || echo nope | tr a-z A-Z && echo done\n");
+
+    assert_eq!(stdout(&output), "NOPE\ndone\n");
+}
+
+#[test]
+fn status_flows_through_an_and_or_list() {
+    let output = run("sh -c 'exit 3' || echo $?\nnope-not-a-command || echo $?\nfalse && true\n");
+
+    assert_eq!(stdout(&output), "3\n127\n");
+    assert!(stderr(&output).contains("nope-not-a-command: command not found"));
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn semicolons_run_every_command_in_order() {
+    let output = run("false ; echo one;echo two | tr a-z A-Z ; nope-not-a-command; echo $? ;\n");
+
+    assert_eq!(stdout(&output), "one\nTWO\n127\n");
+    assert_eq!(stderr(&output), "nope-not-a-command: command not found\n");
+}
+
+#[test]
+fn comments_are_ignored_on_the_command_line() {
+    let output =
+        run("# a whole-line comment\necho hi # it's ignored; echo no\necho foo#bar '#kept'\n");
+
+    assert_eq!(stdout(&output), "hi\nfoo#bar #kept\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn trailing_ampersand_runs_a_command_in_the_background() {
+    let started = std::time::Instant::now();
+    let output =
+        run("sleep 5 > /dev/null 2> /dev/null &\nsleep 5 >/dev/null 2>/dev/null&\necho 'a &'\n");
+
+    assert!(started.elapsed().as_secs() < 4);
+    assert_eq!(stdout(&output), "a &\n");
+    let text = stderr(&output);
+    let jobs: Vec<&str> = text.lines().collect();
+    assert_eq!(jobs.len(), 2, "{:?}", jobs);
+    assert!(jobs[0].starts_with("[1] "));
+    assert!(jobs[1].starts_with("[2] "));
+    assert!(jobs[1][4..].parse::<u32>().is_ok());
+}
+
+#[test]
+fn jobs_lists_running_jobs_and_reports_finished_ones_once() {
+    let script = "sleep 5 >/dev/null 2>/dev/null &\nsleep 0 &\nsleep 0.3\njobs\njobs\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "[1]-  Running                 sleep 5 >/dev/null 2>/dev/null &\n\
+         [2]+  Done                    sleep 0\n\
+         [1]+  Running                 sleep 5 >/dev/null 2>/dev/null &\n"
+    );
+}
+
+#[test]
+fn fg_waits_for_a_job_and_returns_its_status() {
+    let script =
+        "sh -c 'sleep 0.2; exit 3' &\nsh -c 'exit 4' &\nfg %1\necho $?\nfg\necho $?\nfg\nfg %9\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "sh -c 'sleep 0.2; exit 3'\n3\nsh -c 'exit 4'\n4\n"
+    );
+    assert!(stderr(&output).ends_with("fg: no such job\nfg: no such job\n"));
+}
+
+#[test]
+fn background_jobs_run_in_their_own_process_group() {
+    let tmp = TempDir::new("job-group");
+    // Field 5 of /proc/PID/stat is the process group
+    let script = "sh -c '[ $(cut -d\" \" -f5 /proc/$$/stat) = $$ ] && echo own' > job.txt &\n\
+                  fg\n\
+                  sh -c '[ $(cut -d\" \" -f5 /proc/$$/stat) = $$ ] || echo shared'\n\
+                  cat job.txt\n";
+    let output = run_in(tmp.path(), script);
+
+    assert!(stdout(&output).ends_with("\nshared\nown\n"));
+}
+
+#[test]
+fn shell_listings_can_be_piped() {
+    let script = "export -n FOO=bar\nset | grep '^FOO='\n\
+                  alias ll='ls -l'\nalias | cat\nalias ll nope | tr a-z A-Z\n\
+                  history 1 | cat\n\
+                  sleep 5 >/dev/null 2>/dev/null &\njobs | cat\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "FOO=bar\n\
+         alias ll='ls -l'\n\
+         ALIAS LL='LS -L'\n    \
+         6  history 1 | cat\n\
+         [1]+  Running                 sleep 5 >/dev/null 2>/dev/null &\n"
+    );
+    assert!(stderr(&output).contains("alias: nope: not found\n"));
+}
+
+#[test]
+fn builtin_filters_run_in_the_middle_of_a_pipeline() {
+    let tmp = TempDir::new("filters");
+    for name in ["foo.rs", "bar.rs", "food.txt"] {
+        std::fs::write(tmp.path().join(name), "").unwrap();
+    }
+    let output = run_in(
+        tmp.path(),
+        "ls | grep foo | wc -l\necho a b c | wc -w\nprintf 'one two\\nthree\\n' | wc\n",
+    );
+
+    assert_eq!(stdout(&output), "2\n3\n      2       3      14\n");
+}
+
+#[test]
+fn builtin_middle_stages_stream_large_outputs() {
+    let output = run("seq 100000 | grep 9 | wc -l\nseq 100000 | grep 9 | cat | wc -l\n");
+
+    assert_eq!(stdout(&output), "40951\n40951\n");
+}
+
+#[test]
+fn quoted_stream_duplication_in_a_pipeline_is_an_argument() {
+    let output = run("ls '2>&1' | cat\nsh -c 'echo \"$1\"' - \"2>&1\" | cat\n");
+
+    assert_eq!(stdout(&output), "2>&1\n");
+    assert!(stderr(&output).contains("2>&1"), "{}", stderr(&output));
+}
+
+#[test]
+fn stages_honor_their_own_redirections() {
+    let tmp = TempDir::new("stage-redirect");
+    std::fs::write(tmp.path().join("f"), "pear\napple\nfig\n").unwrap();
+    let script = "echo a | cat > out\ncat out\n\
+                  sort < f | head -n 2\n\
+                  echo x | grep x > g | wc -l\ncat g\n\
+                  echo ignored | cat <<< here | cat\n\
+                  grep x missing 2> err | cat\ncat err\n";
+    let output = run_in(tmp.path(), script);
+
+    assert_eq!(
+        stdout(&output),
+        "a\napple\nfig\n0\nx\nhere\ngrep: missing: No such file or directory\n"
+    );
+    assert!(output.stderr.is_empty(), "{}", stderr(&output));
+}
+
+#[test]
+fn exit_with_running_jobs_needs_a_second_exit() {
+    let output = run("sleep 1 &\nexit\necho still here\nexit\nexit 3\necho gone\n");
+    assert_eq!(stdout(&output), "still here\n");
+    // After the job's `[1] PID` line
+    assert!(stderr(&output).ends_with("\nThere are running jobs.\nThere are running jobs.\n"));
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = run("sleep 1 &\nexit -f 4\necho gone\n");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(output.status.code(), Some(4));
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use common::{run, run_with, stderr, stdout, TempDir};
+
+#[test]
+fn exported_variables_reach_child_processes() {
+    let output = run("export GREETING=hello\nsh -c 'echo $GREETING'\n");
+
+    assert_eq!(stdout(&output), "hello\n");
+}
+
+#[test]
+fn export_values_may_contain_equals_signs() {
+    let output = run(
+        "export URL=http://x?a=b\nsh -c 'echo $URL'\nexport -n NAME=a=b=c\necho $NAME\n\
+         export EMPTY==\nsh -c 'echo \"[$EMPTY]\"'\n",
+    );
+
+    assert_eq!(stdout(&output), "http://x?a=b\na=b=c\n[=]\n");
+}
+
+#[test]
+fn bare_export_lists_variables_sorted() {
+    let output = run_with("export\n", |command| {
+        command.env_clear().env("ZED", "last").env("ALPHA", "first");
+    });
+
+    assert_eq!(stdout(&output), "ALPHA=first\nZED=last\n");
+}
+
+#[test]
+fn export_rejects_invalid_names() {
+    let output = run("unset OK 2bad; echo $?\nexport 1BAD=x\n");
+
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "unset: `2bad': not a valid identifier\n\
+         export: `1BAD=x': not a valid identifier\n"
+    );
+}
+
+#[test]
+fn unset_removes_variables_and_ignores_missing_ones() {
+    let output =
+        run("export A=1 B=2 C=3\nunset A C NEVER_SET\nsh -c 'echo \"[$A][$B][$C]\"'\nunset\n");
+
+    assert_eq!(stdout(&output), "[][2][]\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn export_n_keeps_the_value_but_stops_exporting_it() {
+    let output = run(
+        "export FOO=kept\nexport -n FOO\nsh -c 'echo \"[$FOO]\"'\nexport FOO\nsh -c 'echo \"[$FOO]\"'\n",
+    );
+
+    assert_eq!(stdout(&output), "[]\n[kept]\n");
+}
+
+#[test]
+fn variables_expand_in_commands() {
+    let output = run_with(
+        "echo $HOME/bin ${HOME}\necho \"in $HOME quotes\" '$HOME'\ncd $HOME\npwd\necho [$UNSET_VAR] $ end\n",
+        |command| {
+            command.env("HOME", "/tmp").env_remove("UNSET_VAR");
+        },
+    );
+
+    assert_eq!(
+        stdout(&output),
+        "/tmp/bin /tmp\nin /tmp quotes $HOME\n/tmp\n[] $ end\n"
+    );
+}
+
+#[test]
+fn shell_variables_expand_too() {
+    let output = run("export LOCAL=here\nexport -n LOCAL\necho $LOCAL\n");
+
+    assert_eq!(stdout(&output), "here\n");
+}
+
+#[test]
+fn question_mark_expands_to_the_last_status() {
+    let output = run(
+        "echo $?\nsh -c 'exit 3'\necho $?\nnope-not-a-command\necho ${?}\necho hi | sh -c 'exit 5'\necho $? '$?'\n",
+    );
+
+    assert_eq!(stdout(&output), "0\n3\n127\n5 $?\n");
+}
+
+#[test]
+fn escaped_and_single_quoted_dollars_stay_literal() {
+    let output = run_with(
+        "echo \\$HOME '$HOME' \"$HOME\" \"\\$HOME\" \"a\\\\$HOME\"\n",
+        |cmd| {
+            cmd.env("HOME", "/home/me");
+        },
+    );
+
+    assert_eq!(stdout(&output), "$HOME $HOME /home/me $HOME a\\/home/me\n");
+}
+
+#[test]
+fn tilde_expands_in_any_argument() {
+    let home = TempDir::new("tilde-home");
+    std::fs::write(home.path().join("notes.txt"), "remember\n").unwrap();
+    let output = run_with("cat ~/notes.txt\necho '~/notes.txt'\n", |cmd| {
+        cmd.env("HOME", home.path());
+    });
+
+    assert_eq!(stdout(&output), "remember\n~/notes.txt\n");
+}
+
+#[test]
+fn command_substitution_splices_output() {
+    let script = "echo today is $(echo  Monday   the  1st)\n\
+                  echo \"[$(printf 'a  b\\n\\n')]\"\n\
+                  echo \"$(echo \"$(echo ')')\")\" $(echo $(echo deep))\n\
+                  echo $(echo 'x && y') && echo after\n\
+                  echo '$(echo no)' \\$(echo no)\n\
+                  echo $(seq 1 20000 | wc -l)\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "today is Monday the 1st\n[a  b]\n) deep\nx && y\nafter\n$(echo no) $(echo no)\n20000\n"
+    );
+}
+
+#[test]
+fn command_substitution_sets_the_status() {
+    let output = run("$(exit 3)\necho $?\necho $(false) $?\necho $(exit 4; echo unreached)done\n");
+
+    assert_eq!(stdout(&output), "3\n1\ndone\n");
+}
+
+#[test]
+fn bare_assignments_keep_substituted_output_whole() {
+    let script = "x=$(echo hi)\necho $x\n\
+                  y=$(printf 'a  b\\nc') z=\"$y\"!\necho \"$z\"\n\
+                  export E=1\nE=two\nsh -c 'echo $E'\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "hi\na  b\nc!\ntwo\n");
+    assert!(output.stderr.is_empty(), "{}", stderr(&output));
+}
+
+#[test]
+fn assignment_status_is_the_substituted_command() {
+    let output =
+        run("x=$(false)\necho $?\nout=$(exit 3) || echo handled $?\nok=$(true) || echo skipped\n");
+
+    assert_eq!(stdout(&output), "1\nhandled 3\n");
+}
+
+#[test]
+fn unclosed_command_substitution_is_an_error() {
+    let output = run("echo $(echo open\n");
+
+    assert_eq!(
+        stderr(&output),
+        "unexpected EOF while looking for matching `)'\n"
+    );
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn unset_variables_expand_to_nothing_without_set_u() {
+    let output = run("echo [$POPPER_UNSET_VAR]\n");
+
+    assert_eq!(stdout(&output), "[]\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn set_u_stops_a_script_at_an_unbound_variable() {
+    let script = "set -u\necho $? \"$HOME\" > /dev/null\necho before\n\
+                  echo ${POPPER_UNSET_VAR}\necho after\n";
+    let output = run(script);
+
+    assert_eq!(stdout(&output), "before\n");
+    assert_eq!(stderr(&output), "POPPER_UNSET_VAR: unbound variable\n");
+    assert_eq!(output.status.code(), Some(1));
+
+    let output = run("set -o nounset\nset +u\necho [$POPPER_UNSET_VAR]\nset -x\n");
+    assert_eq!(stdout(&output), "[]\n");
+    assert_eq!(stderr(&output), "set: -x: invalid option\n");
+}
+
+#[test]
+fn bare_set_lists_shell_and_environment_variables() {
+    let script = "export -n POPPER_LOCAL='two words'\nexport -n POPPER_PLAIN=/tmp/x\nset\n";
+    let output = run_with(script, |command| {
+        command
+            .env_clear()
+            .env("HISTFILE", "")
+            .env("ZZ_EXPORTED", "it's");
+    });
+
+    assert_eq!(
+        stdout(&output),
+        "HISTFILE=''\nPOPPER_LOCAL='two words'\n\
+         POPPER_PLAIN=/tmp/x\nZZ_EXPORTED='it'\\''s'\n"
+    );
+}
+
+#[test]
+fn indirect_expansion_and_prefix_listing() {
+    let script = "export -n a=b\nexport -n b=hi\necho ${!a}\n\
+                  export -n POPPER_PX_ONE=1\nexport POPPER_PX_TWO=2\n\
+                  echo ${!POPPER_PX_*}\necho \"${!POPPER_PX@}\"\necho [${!nothing}] [${!a\n";
+    let output = run(script);
+
+    assert_eq!(
+        stdout(&output),
+        "hi\nPOPPER_PX_ONE POPPER_PX_TWO\nPOPPER_PX_ONE POPPER_PX_TWO\n[] [${!a\n"
+    );
+}
+
+#[test]
+fn env_lists_the_environment_or_runs_a_command_with_additions() {
+    let script = "env | grep POPPER_ENV\n\
+                  env POPPER_ENV=one POPPER_MORE=a=b sh -c 'echo $POPPER_ENV $POPPER_MORE'\n\
+                  echo \"[$POPPER_ENV]\"\n\
+                  env POPPER_ENV=two | grep POPPER_ENV\n\
+                  env POPPER_ENV=three sh -c 'echo $POPPER_ENV' | cat\n";
+    let output = run_with(script, |command| {
+        command.env("POPPER_ENV", "zero");
+    });
+
+    assert_eq!(
+        stdout(&output),
+        "POPPER_ENV=zero\none a=b\n[zero]\nPOPPER_ENV=two\nthree\n"
+    );
+}